@@ -1,12 +1,9 @@
 use crate::cartridge::Cartridge;
 use crate::cpu_recievable::Recievables;
+use crate::gameboy::Model;
 // use rodio::{buffer::SamplesBuffer, source::Source, Decoder, OutputStream, OutputStreamHandle};
 use serde::{Deserialize, Serialize};
 
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Write;
-
 /*
 Memory Map
 ==========
@@ -15,7 +12,7 @@ Memory Map
 = 8000-9FFF = 8KB  Video RAM
 = A000-BFFF = 8KB  External RAM (from cartridge)
 = C000-CFFF = 4KB  Work RAM Bank 0
-= D000-EFFF = 4KB  Work RAM Bank 1
+= D000-DFFF = 4KB  Work RAM Bank 1 (switchable 1-7 on CGB)
 = E000-FDFF = Mirror of C000-DDFF
 = FE00-FE9F = OAM RAM
 = FEA0-FEFF = Unused. Reads return 0xFF when OAM blocked, otherwise 00
@@ -26,15 +23,18 @@ Memory Map
 
 I/O Register Details
 ====================
-$FF00     $FF02     DMG     Port/Mode
+$FF00               DMG     Joypad
+$FF01     $FF02     DMG     Serial
 $FF04     $FF07     DMG     Port/Mode
 $FF10     $FF26     DMG     Sound
 $FF30     $FF3F     DMG     Waveform RAM
 $FF40     $FF4B     DMG     LCD
+$FF4C               CGB     KEY0, written by the boot ROM to pick DMG compatibility mode
+$FF4D               CGB     KEY1, Prepare Speed Switch
 $FF4F               CGB     VRAM Bank Select
 $FF50               DMG     Set to non-zero to disable boot ROM
 $FF51     $FF55     CGB     HDMA
-$FF68     $FF69     CGB     BCP/OCP
+$FF68     $FF6B     CGB     BCP/OCP
 $FF70               CGB     WRAM Bank Select
 */
 
@@ -42,15 +42,22 @@ $FF70               CGB     WRAM Bank Select
 pub struct Bus {
     rom: Cartridge,
     ram: Vec<u8>, // Most of this will get shadowed as the code is filled in
+    wram: Vec<u8>, // 8 banks of 0x1000, only the first 2 are reachable on DMG
+    wram_bank: u8, // 0xFF70, SVBK
+    model: Model,
+    cgb: bool, // CGB mode, false when running a DMG cart on a CGB
+    sgb: Option<crate::sgb::Sgb>,
+    double_speed: bool,
+    speed_switch_armed: bool, // KEY1 bit 0, the next STOP switches speed
+    half_cycle: bool, // in double speed the apu only ticks every other cpu cycle
     ppu: crate::ppu::PPU,
     apu: crate::apu::APU,
     timer: crate::timer::Timer,
+    serial: crate::serial::Serial,
     pub joypad: Joypad,
     bios: Option<Vec<u8>>,
     pub reg_ie: InterruptRegister, // 0xFFFF
     pub reg_if: InterruptRegister, // 0xFF0F
-    #[serde(skip)]
-    testfile: Option<File>, // TODO: remove. This is for testing only. Specifcally to hold the outputted serial data when running blargg's testroms
 }
 
 impl Clone for Bus {
@@ -58,14 +65,22 @@ impl Clone for Bus {
         Bus {
             rom: self.rom.clone(),
             ram: self.ram.clone(),
+            wram: self.wram.clone(),
+            wram_bank: self.wram_bank,
+            model: self.model,
+            cgb: self.cgb,
+            sgb: self.sgb.clone(),
+            double_speed: self.double_speed,
+            speed_switch_armed: self.speed_switch_armed,
+            half_cycle: self.half_cycle,
             ppu: self.ppu.clone(),
             apu: self.apu.clone(),
             timer: self.timer.clone(),
+            serial: self.serial.clone(),
             reg_if: self.reg_if.clone(),
             reg_ie: self.reg_ie.clone(),
             bios: self.bios.clone(),
             joypad: self.joypad.clone(),
-            testfile: None,
         }
     }
 }
@@ -87,6 +102,76 @@ impl Bus {
     pub fn get_canvas(&self) -> crate::ppu::Canvas {
         return self.ppu.get_canvas();
     }
+    pub fn get_sgb_canvas(&self) -> Option<Vec<u32>> {
+        self.sgb.as_ref().map(|sgb| sgb.get_canvas(self.ppu.get_screen()))
+    }
+    pub fn is_rumbling(&self) -> bool {
+        self.rom.is_rumbling()
+    }
+    pub fn rom_info(&self) -> &crate::cartridge::RomInfo {
+        self.rom.rom_info()
+    }
+    pub fn model(&self) -> Model {
+        self.model
+    }
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb
+    }
+
+    // A CGB boot rom decides the mode itself, otherwise we go off the cart header
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.sgb = if model == Model::SGB { Some(crate::sgb::Sgb::new()) } else { None };
+        let cgb = model == Model::CGB && (self.bios.is_some() || self.rom.rom_info().supports_cgb());
+        self.set_cgb_mode(cgb);
+    }
+
+    fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.ppu.set_cgb_mode(cgb);
+        self.serial.set_cgb_mode(cgb);
+    }
+
+    pub fn set_serial_link(&mut self, link: Box<dyn crate::serial::SerialLink>) {
+        self.serial.set_link(link)
+    }
+    pub fn take_serial_link(&mut self) -> Box<dyn crate::serial::SerialLink> {
+        self.serial.take_link()
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // https://gbdev.io/pandocs/#ff4d-key1-cgb-mode-only-prepare-speed-switch
+    // Called on STOP, returns true if the speed was switched instead of stopping
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    fn wram_loc(&self, loc: u16) -> usize {
+        let bank = match loc {
+            0xC000..=0xCFFF => 0,
+            // Bank 0 can't be selected, writing 0 gives bank 1
+            _ if self.cgb => (self.wram_bank as usize & 0b111).max(1),
+            _ => 1,
+        };
+        bank * 0x1000 + (loc as usize & 0xFFF)
+    }
+    pub fn has_battery(&self) -> bool {
+        self.rom.has_battery()
+    }
+    pub fn export_save_ram(&self) -> Vec<u8> {
+        self.rom.export_save_ram()
+    }
+    pub fn import_save_ram(&mut self, data: &[u8]) {
+        self.rom.import_save_ram(data)
+    }
 
     pub fn with_bios(rom: Cartridge, bios: Vec<u8>) -> Self {
         let mut bus = Self::new(rom);
@@ -96,9 +181,11 @@ impl Bus {
 
     pub fn new(rom: Cartridge) -> Self {
         let ram = [0u8; 0x10000].to_vec();
+        let wram = [0u8; 0x8000].to_vec();
         let ppu = crate::ppu::PPU::new();
         let apu = crate::apu::APU::new();
         let timer = crate::timer::Timer::new();
+        let serial = crate::serial::Serial::new();
         let reg_if = InterruptRegister { data: 0 };
         let reg_ie = InterruptRegister { data: 0 };
         let bios = None;
@@ -106,44 +193,90 @@ impl Bus {
             pins: 0b1111,
             last: 0,
         };
-
-        let mut testfile: Option<File> = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open("serial")
-            .ok();
         Bus {
             rom,
             ram,
+            wram,
+            wram_bank: 1,
+            model: Model::DMG,
+            cgb: false,
+            sgb: None,
+            double_speed: false,
+            speed_switch_armed: false,
+            half_cycle: false,
             ppu,
             apu,
             timer,
+            serial,
             joypad,
             reg_if,
             reg_ie,
             bios,
-            testfile,
+        }
+    }
+
+    // https://gbdev.io/pandocs/#oam-dma-transfer
+    // While OAM DMA is copying it has the memory buses, so the cpu can only use
+    // the io registers and HRAM
+    fn is_dma_blocked(&self, loc: u16) -> bool {
+        loc < 0xFF00 && self.ppu.is_dma_blocking()
+    }
+
+    pub fn set_memory_blocking(&mut self, blocking: bool) {
+        self.ppu.set_blocking(blocking)
+    }
+
+    pub fn is_in_vblank(&self) -> bool {
+        self.ppu.is_in_vblank()
+    }
+
+    pub fn update_joypad(&mut self, buttonmap: u8) {
+        if self.joypad.update_joypad(buttonmap) {
+            self.reg_if.data |= 0b10000;
         }
     }
 
     pub fn read(&self, loc: u16) -> u8 {
+        if self.is_dma_blocked(loc) {
+            return 0xFF;
+        }
+        self.read_bus(loc)
+    }
+
+    // Reads without the OAM DMA restriction, for the DMAs themselves
+    fn read_bus(&self, loc: u16) -> u8 {
         match loc {
             0x0000..=0xFF if self.bios.is_some() => self.bios.as_ref().unwrap()[loc as usize],
+            // The CGB boot rom is 0x900 bytes, with a hole for the cartridge header
+            0x0200..=0x08FF if self.bios.as_ref().is_some_and(|b| b.len() > loc as usize) => {
+                self.bios.as_ref().unwrap()[loc as usize]
+            }
             0x0000..=0x3FFF => self.rom.read(loc),
             0x4000..=0x7FFF => self.rom.read(loc), // upper rom banks
             0x8000..=0x9FFF => self.ppu.read(loc),
-            0xC000..=0xCFFF => self.ram[loc as usize],
-            0xD000..=0xDFFF => self.ram[loc as usize],
-            0xE000..=0xFDFF => self.read(loc - 0xE000 + 0xC000),
+            0xC000..=0xDFFF => self.wram[self.wram_loc(loc)],
+            0xE000..=0xFDFF => self.read_bus(loc - 0xE000 + 0xC000),
             0xA000..=0xBFFF => self.rom.read(loc), // external RAM
             0xFE00..=0xFE9F => self.ppu.readOAM(loc),
             0xFEA0..=0xFEFF => 0x00, // Unused
+            0xFF00 if self.sgb.is_some() => self.sgb.as_ref().unwrap().read_joypad(self.joypad.pins),
             0xFF00..=0xFF00 => self.joypad.pins,
+            0xFF01..=0xFF02 => self.serial.read(loc),
             0xFF04..=0xFF07 => self.timer.read(loc),
             0xFF0F => self.reg_if.data,
             0xFF10..=0xFF26 => self.apu.read(loc),
             0xFF30..=0xFF3F => self.apu.read(loc),
+            0xFF4D if self.cgb => {
+                (if self.double_speed { 0b10000000 } else { 0 })
+                    | 0b01111110
+                    | if self.speed_switch_armed { 1 } else { 0 }
+            }
             0xFF40..=0xFF4F => { self.ppu.read_reg(loc) },
+            0xFF51..=0xFF55 if self.cgb => self.ppu.hdma.read(loc),
+            0xFF51..=0xFF55 => 0xFF,
+            0xFF68..=0xFF6B => self.ppu.read_reg(loc),
+            0xFF70 if self.cgb => 0b11111000 | self.wram_bank,
+            0xFF70 => 0xFF,
             0xFF00..=0xFF7F => { print!("[UNIMPLEMENTED: Reading IO Register: {:04X}]\n{:19}", loc, ""); 0},
             0xFF80..=0xFFFE => self.ram[loc as usize], // HRAM
             0xFFFF => self.reg_ie.data,
@@ -152,27 +285,45 @@ impl Bus {
     }
 
     pub fn write(&mut self, loc: u16, val: u8) {
+        if self.is_dma_blocked(loc) {
+            return;
+        }
         match loc {
             0x0000..=0x3FFF => self.rom.write(loc, val),
             0x4000..=0x7FFF => self.rom.write(loc, val), // upper rom banks
             0x8000..=0x9FFF => self.ppu.write(loc, val),
-            0xC000..=0xCFFF => self.ram[loc as usize] = val,
-            0xD000..=0xDFFF => self.ram[loc as usize] = val,
+            0xC000..=0xDFFF => {
+                let l = self.wram_loc(loc);
+                self.wram[l] = val
+            }
             0xE000..=0xFDFF => self.write(loc - 0xE000 + 0xC000, val),
             0xA000..=0xBFFF => self.rom.write(loc, val), // external RAM
             0xFE00..=0xFE9F => self.ppu.writeOAM(loc, val),
-            0xFF00..=0xFF00 => self.joypad.write(val),
+            0xFF00..=0xFF00 => {
+                if self.joypad.write(val) {
+                    self.reg_if.data |= 0b10000;
+                }
+                if let Some(sgb) = self.sgb.as_mut() {
+                    sgb.write_joypad(val, &self.ppu);
+                }
+            }
             0xFF50 => self.bios = None,
             0xFEA0..=0xFEFF => (), // Unused
-            0xFF01 if self.testfile.is_some() => {
-                self.testfile.as_mut().unwrap().write(&[val]);
-            } // Serial transfer data
-            0xFF02 => (),          // Serial transfer control
+            0xFF01..=0xFF02 => self.serial.write(loc, val),
             0xFF04..=0xFF07 => self.timer.write(loc, val),
             0xFF0F => self.reg_if.data = val,
             0xFF10..=0xFF26 => self.apu.write(loc, val),
             0xFF30..=0xFF3F => self.apu.write(loc, val),
+            // Bit 2 set means the cart has no CGB support, only the boot rom can write this
+            0xFF4C if self.bios.is_some() && self.model == Model::CGB => self.set_cgb_mode(val & 0b100 == 0),
+            0xFF4C => (),
+            0xFF4D if self.cgb => self.speed_switch_armed = val & 0b1 != 0,
             0xFF40..=0xFF4F => self.ppu.write_reg(loc, val),
+            0xFF51..=0xFF55 if self.cgb => self.ppu.hdma.write(loc, val),
+            0xFF51..=0xFF55 => (),
+            0xFF68..=0xFF6B => self.ppu.write_reg(loc, val),
+            0xFF70 if self.cgb => self.wram_bank = val & 0b111,
+            0xFF70 => (),
             0xFF00..=0xFF7F => {
                 print!(
                     "[UNIMPLEMENTED: Writing IO Register: {:04X}]\n{:19}",
@@ -188,35 +339,68 @@ impl Bus {
     fn dma_transfer(&mut self) {
         match self.ppu.dma.next() {
             Some((from, to)) => {
-                let val = self.read(from);
-                self.write(to, val);
+                let val = self.read_bus(from);
+                self.ppu.dma_write_oam(to, val);
             }
             None => (),
         }
     }
 
+    // Copies 2 bytes per cpu cycle, or 1 in double speed
+    fn hdma_transfer(&mut self) {
+        let n = if self.double_speed { 1 } else { 2 };
+        for _ in 0..n {
+            if let Some((from, to)) = self.ppu.hdma.next() {
+                let val = self.read_bus(from);
+                self.ppu.dma_write(to, val);
+            }
+        }
+    }
+
+    // The cpu is stalled while a general purpose or H-Blank DMA is copying
+    pub fn is_hdma_transferring(&self) -> bool {
+        self.ppu.hdma.is_transferring()
+    }
+
+    pub fn set_hdma_paused(&mut self, paused: bool) {
+        self.ppu.hdma.set_paused(paused)
+    }
+
     pub fn cpu_tick(&mut self) {
-        // CPU runs at 1MHz
-        // PPU runs at 2MHz
-        self.ppu.tick();
-        self.ppu.tick();
+        // CPU runs at 1MHz, one cycle is 4 clocks
+        // PPU draws one dot per clock, at 4MHz
+        // In double speed the CPU, timer and OAM DMA run at 2MHz,
+        // so the PPU and APU only get half as much done per cpu cycle
+        let dots = if self.double_speed { 2 } else { 4 };
+        if self.double_speed {
+            self.half_cycle = !self.half_cycle;
+        }
+        for _ in 0..dots {
+            self.ppu.tick();
+        }
         self.timer.tick();
+        self.serial.tick();
         self.dma_transfer();
+        self.hdma_transfer();
         // TODO: call apu tick
-        self.apu.tick();
+        if !self.double_speed || self.half_cycle {
+            self.apu.tick();
+        }
     }
 
+    // The stack can be anywhere, so it goes through the same mapping as everything else
     pub fn stack_push(&mut self, sp: usize, data: u8) {
-        self.ram[sp] = data;
+        self.write(sp as u16, data)
     }
 
     pub fn stack_pop(&self, sp: usize) -> u8 {
-        self.ram[sp]
+        self.read(sp as u16)
     }
 
     pub fn set_recievables(&mut self, recievables: Recievables) {
         self.ppu.set_recievables(recievables.clone());
         self.timer.set_recievables(recievables.clone());
+        self.serial.set_recievables(recievables.clone());
     }
 }
 
@@ -234,13 +418,13 @@ impl InterruptRegister {
     pub fn get_lcdstat(&mut self) -> bool {
         (self.data & 0b10) > 0
     }
-    pub fn get_joypad(&mut self) -> bool {
+    pub fn get_timer(&mut self) -> bool {
         (self.data & 0b100) > 0
     }
-    pub fn get_timer(&mut self) -> bool {
+    pub fn get_serial(&mut self) -> bool {
         (self.data & 0b1000) > 0
     }
-    pub fn get_serial(&mut self) -> bool {
+    pub fn get_joypad(&mut self) -> bool {
         (self.data & 0b10000) > 0
     }
 }
@@ -269,13 +453,20 @@ impl Joypad {
     pub fn set_right_a(&mut self, low: bool) {
         self.pins = self.pins & 0b111110 | (if low { 0 } else { 1 } << 0)
     }
-    pub fn write(&mut self, val: u8) {
+    pub fn any_line_low(&self) -> bool {
+        self.pins & 0b1111 != 0b1111
+    }
+    // https://gbdev.io/pandocs/#ff00-p1joyp-joypad-rw
+    // Both return true if any of the lower 4 lines went from high to low,
+    // which is what requests the joypad interrupt
+    pub fn write(&mut self, val: u8) -> bool {
         // lower 4 bits are read only
         self.pins = (self.pins & 0b00001111) | (val & 0b00110000);
-        self.update_joypad(self.last);
+        self.update_joypad(self.last)
     }
 
-    pub fn update_joypad(&mut self, buttonmap: u8) {
+    pub fn update_joypad(&mut self, buttonmap: u8) -> bool {
+        let was_high = self.pins & 0b1111;
         self.last = buttonmap;
         use crate::gameboy;
         let dir = self.pins & 0b010000 == 0;
@@ -294,5 +485,52 @@ impl Joypad {
         self.set_right_a(
             dir && (buttonmap & gameboy::BUT_RIGHT > 0) || act && (buttonmap & gameboy::BUT_A > 0),
         );
+        was_high & !self.pins & 0b1111 != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_test_bus() -> Bus {
+        Bus::new(Cartridge::test(vec![]))
+    }
+
+    // Runs the bus until f is true, returning how many cpu cycles it took
+    fn cycles_until(bus: &mut Bus, f: impl Fn(&Bus) -> bool) -> usize {
+        let mut cycles = 0;
+        while !f(bus) {
+            bus.cpu_tick();
+            cycles += 1;
+            assert!(cycles < 100_000, "never got there");
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_ppu_cycles() {
+        // https://gbdev.io/pandocs/#lcd-status-register
+        // A line is 456 dots, 114 cpu cycles, and a frame is 154 lines
+        let mut bus = create_test_bus();
+        bus.write(0xFF40, 0x91);
+        cycles_until(&mut bus, |b| b.read(0xFF44) == 1);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 2), 114);
+        cycles_until(&mut bus, |b| b.read(0xFF44) == 0);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 1), 114);
+        cycles_until(&mut bus, |b| b.read(0xFF44) == 0);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 0 && b.read(0xFF41) & 0b11 == 0b10), 0);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 1), 114);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 0), 154 * 114 - 114);
+
+        // OAM scan is 80 dots, and drawing a plain line is 172 more before H-Blank
+        cycles_until(&mut bus, |b| b.read(0xFF44) == 5);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF41) & 0b11 == 0b11), 20);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF41) & 0b11 == 0b00), 43);
+
+        // In double speed the cpu gets twice as many cycles per line
+        bus.double_speed = true;
+        cycles_until(&mut bus, |b| b.read(0xFF44) == 10);
+        assert_eq!(cycles_until(&mut bus, |b| b.read(0xFF44) == 11), 228);
     }
 }
@@ -1,5 +1,11 @@
+mod header;
+mod rtc;
+
 use serde::{Serialize, Deserialize};
 
+pub use header::RomInfo;
+use rtc::Rtc;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Cartridge {
     mapper: Mapper,
@@ -7,9 +13,38 @@ pub struct Cartridge {
     ram: Vec<u8>,
     ramBanks: usize,
     romBanks: usize,
+    rtc: Option<Rtc>,
+    battery: bool,
+    info: RomInfo,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    NoRom,
+    Truncated(usize, usize), // size needed, size given
+    UnsupportedMapper(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
 }
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NoRom => write!(f, "No rom was loaded"),
+            Self::Truncated(needed, given) => {
+                write!(f, "Rom is truncated: expected {} bytes but got {}", needed, given)
+            }
+            Self::UnsupportedMapper(t) => write!(f, "Unsupported cartridge type: {:02X}", t),
+            Self::InvalidRomSize(s) => write!(f, "Invalid rom size in header: {:02X}", s),
+            Self::InvalidRamSize(s) => write!(f, "Invalid ram size in header: {:02X}", s),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 const RAM_BANK_SIZE: usize = 8192;
 const ROM_BANK_SIZE: usize = 16384;
+const MBC2_RAM_SIZE: usize = 512; // half bytes, built into the mapper
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 enum Mapper { // https://gbdev.io/pandocs/#_0147-cartridge-type
@@ -18,6 +53,18 @@ enum Mapper { // https://gbdev.io/pandocs/#_0147-cartridge-type
     // https://gbdev.io/pandocs/#mbc1
     MBC1(bool, usize, usize, bool),
     // ram enabled, bank low, bank high, simple mode
+
+    // https://gbdev.io/pandocs/#mbc2
+    MBC2(bool, usize),
+    // ram enabled, rom bank
+
+    // https://gbdev.io/pandocs/#mbc3
+    MBC3(bool, usize, u8, u8),
+    // ram and rtc enabled, rom bank, ram bank or rtc register, last latch write
+
+    // https://gbdev.io/pandocs/#mbc5
+    MBC5(bool, usize, u8, bool),
+    // ram enabled, rom bank, ram bank register, has rumble motor
 }
 
 impl Cartridge {
@@ -30,76 +77,657 @@ impl Cartridge {
             mapper: Mapper::ROM,
             ramBanks: 0,
             romBanks: 0,
-            rom: data,
             ram: Vec::new(),
+            rtc: None,
+            battery: false,
+            info: RomInfo::from_header(&data),
+            rom: data,
         }
     }
 
-    pub fn from_data(data: Vec<u8>) -> Self {
-        let mapper = Cartridge::check_mapper(&data);
+    pub fn from_data(data: Vec<u8>) -> Result<Self, LoadError> {
+        if data.len() < header::HEADER_END {
+            return Err(LoadError::Truncated(header::HEADER_END, data.len()));
+        }
+        let mapper = Cartridge::check_mapper(&data)?;
+        if data[0x148] > 0x08 {
+            return Err(LoadError::InvalidRomSize(data[0x148]));
+        }
         let romBanks: usize = 2 * 2u32.pow(data[0x148] as u32) as usize;
+        if data.len() < romBanks * ROM_BANK_SIZE {
+            return Err(LoadError::Truncated(romBanks * ROM_BANK_SIZE, data.len()));
+        }
         let ramBanks: usize = match data[0x149] {
             0x0 => 0,
+            0x1 => 1, // 2KB, rounded up to a full bank
             0x2 => 1,
             0x3 => 4,
             0x4 => 16,
             0x5 => 8,
-            _ => 0,
+            _ => return Err(LoadError::InvalidRamSize(data[0x149])),
         };
-        let ram = Vec::with_capacity(RAM_BANK_SIZE * ramBanks);
+        let ram = match mapper {
+            Mapper::MBC2(_, _) => vec![0u8; MBC2_RAM_SIZE],
+            _ => vec![0u8; RAM_BANK_SIZE * ramBanks],
+        };
+        let rtc = match data[0x147] {
+            0x0F | 0x10 => Some(Rtc::new()),
+            _ => None,
+        };
+        let battery = Cartridge::check_battery(&data);
+        let info = RomInfo::from_header(&data);
         let rom = data;
-        eprintln!("romBanks: {}, ramBanks: {}. Mapper: {:?}", romBanks, ramBanks, mapper);
-        Self {mapper, ramBanks, romBanks, ram, rom }
+        Ok(Self {mapper, ramBanks, romBanks, ram, rom, rtc, battery, info })
+    }
+
+    pub fn rom_info(&self) -> &RomInfo {
+        &self.info
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    // The raw ram in the .sav format other emulators use, with the rtc
+    // footer appended for MBC3 timer carts. Empty if nothing is battery backed
+    pub fn export_save_ram(&self) -> Vec<u8> {
+        if !self.battery {
+            return Vec::new();
+        }
+        let mut data = self.ram[..self.save_ram_size()].to_vec();
+        if let Some(rtc) = &self.rtc {
+            data.extend_from_slice(&rtc.to_save());
+        }
+        data
+    }
+
+    pub fn import_save_ram(&mut self, data: &[u8]) {
+        if !self.battery {
+            return;
+        }
+        let size = self.save_ram_size();
+        let n = size.min(data.len());
+        self.ram[..n].copy_from_slice(&data[..n]);
+        if let Some(rtc) = self.rtc.as_mut() {
+            if data.len() > size {
+                rtc.load_save(&data[size..]);
+            }
+        }
+    }
+
+    // The ram is always allocated in whole banks, but saves only hold what the
+    // header says is on the cart. MBC2 has its ram built in and the header says 0
+    fn save_ram_size(&self) -> usize {
+        match self.mapper {
+            Mapper::MBC2(_, _) => self.ram.len(),
+            _ => self.info.ram_size().min(self.ram.len()),
+        }
+    }
+
+    // On rumble carts bit 3 of the ram bank register drives the motor
+    pub fn is_rumbling(&self) -> bool {
+        match self.mapper {
+            Mapper::MBC5(_, _, ram_bank, true) => ram_bank & 0b1000 != 0,
+            _ => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_rtc_clock(&mut self, clock: std::rc::Rc<dyn rtc::Clock>) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    fn check_battery(data: &[u8]) -> bool {
+        // https://gbdev.io/pandocs/#_0147-cartridge-type
+        matches!(data[0x147], 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF)
     }
 
-    fn check_mapper(data: &Vec<u8>) -> Mapper {
+    fn check_mapper(data: &Vec<u8>) -> Result<Mapper, LoadError> {
         const loc: usize = 0x147;
         match data[loc] {
-            0x0 => Mapper::ROM,
-            0x1 => Mapper::MBC1(false, 1, 0, true),
-            _ => unimplemented!("Unimplemented mapper type: {:02X}", data[loc]),
+            0x0 => Ok(Mapper::ROM),
+            0x1..=0x3 => Ok(Mapper::MBC1(false, 1, 0, true)),
+            0x5..=0x6 => Ok(Mapper::MBC2(false, 1)),
+            0x0F..=0x13 => Ok(Mapper::MBC3(false, 1, 0, 0xFF)),
+            0x19..=0x1B => Ok(Mapper::MBC5(false, 1, 0, false)),
+            0x1C..=0x1E => Ok(Mapper::MBC5(false, 1, 0, true)),
+            t => Err(LoadError::UnsupportedMapper(t)),
         }
     }
 
+    // In simple mode only the first ram bank is reachable, in advanced
+    // mode the upper bank bits select the ram bank
+    fn mbc1_ram_loc(&self, loc: usize, hibank: usize, simple: bool) -> usize {
+        let bank = if simple { 0 } else { hibank };
+        (bank * RAM_BANK_SIZE + (loc & 0x1FFF)) % self.ram.len()
+    }
+
+    // Rumble carts lose bit 3 of the ram bank to the motor
+    fn mbc5_ram_loc(loc: usize, ram_bank: u8, rumble: bool) -> usize {
+        let bank = if rumble { ram_bank & 0b0111 } else { ram_bank };
+        bank as usize * RAM_BANK_SIZE + (loc & 0x1FFF)
+    }
+
     pub fn read(&self, loc: u16) -> u8 {
         match self.mapper {
-            Mapper::ROM => if self.rom.len() < loc.into() { 0x49 } else {self.rom[loc as usize]},
-            Mapper::MBC1(ram, bank, hibank, simple) => { // TODO: add ram
+            Mapper::ROM => if self.rom.len() <= loc.into() { 0x49 } else {self.rom[loc as usize]},
+            Mapper::MBC1(ram, bank, hibank, simple) => {
                 let loc = loc as usize;
-                // Bank ram, rom low, rom high
-                let bankR = hibank * RAM_BANK_SIZE;
-                let bankL = if simple { 0 } else { (hibank << 5) * ROM_BANK_SIZE};
-                let bankH = if simple { if bank == 0 { 1 } else { bank } } else { hibank << 5 | bank} * ROM_BANK_SIZE;
+                // Bank rom low, rom high
+                let bankL = if simple { 0 } else { (hibank << 5) * ROM_BANK_SIZE };
+                let bankH = (hibank << 5 | if bank == 0 { 1 } else { bank }) * ROM_BANK_SIZE;
                 match loc {
-                    0x0000..=0x3FFF => self.rom[bankL + (loc & 0x3FFF)],
-                    0x4000..=0x7FFF => self.rom[bankH + (loc & 0x3FFF)],
-                    0xA000..=0xBFFF => self.ram[bankR + (loc & 0x1FFF)],
+                    0x0000..=0x3FFF => self.rom[(bankL + (loc & 0x3FFF)) % self.rom.len()],
+                    0x4000..=0x7FFF => self.rom[(bankH + (loc & 0x3FFF)) % self.rom.len()],
+                    0xA000..=0xBFFF if ram && !self.ram.is_empty() => {
+                        self.ram[self.mbc1_ram_loc(loc, hibank, simple)]
+                    }
+                    0xA000..=0xBFFF => 0xFF, // open bus while ram is disabled
                     _ => panic!("These read ranges should not be routed to cartridge")
                 }
 
             }
+            Mapper::MBC2(ram, bank) => {
+                let loc = loc as usize;
+                match loc {
+                    0x0000..=0x3FFF => self.rom[loc],
+                    0x4000..=0x7FFF => self.rom[(bank * ROM_BANK_SIZE + (loc & 0x3FFF)) % self.rom.len()],
+                    // Only the low nibble is stored, the upper bits are undefined and read as 1s.
+                    // The 512 entries repeat through the whole external ram range
+                    0xA000..=0xBFFF if ram => self.ram[loc & 0x1FF] | 0xF0,
+                    0xA000..=0xBFFF => 0xFF,
+                    _ => panic!("These read ranges should not be routed to cartridge")
+                }
+            }
+            Mapper::MBC3(enabled, bank, select, _) => {
+                let loc = loc as usize;
+                let bankH = if bank == 0 { 1 } else { bank } * ROM_BANK_SIZE;
+                match loc {
+                    0x0000..=0x3FFF => self.rom[loc],
+                    0x4000..=0x7FFF => self.rom[(bankH + (loc & 0x3FFF)) % self.rom.len()],
+                    0xA000..=0xBFFF if !enabled => 0xFF,
+                    0xA000..=0xBFFF => match (select, &self.rtc) {
+                        (0x00..=0x03, _) if !self.ram.is_empty() => {
+                            self.ram[(select as usize * RAM_BANK_SIZE + (loc & 0x1FFF)) % self.ram.len()]
+                        }
+                        (0x08..=0x0C, Some(rtc)) => rtc.read(select),
+                        _ => 0xFF,
+                    },
+                    _ => panic!("These read ranges should not be routed to cartridge")
+                }
+            }
+            Mapper::MBC5(enabled, bank, ram_bank, rumble) => {
+                let loc = loc as usize;
+                match loc {
+                    0x0000..=0x3FFF => self.rom[loc],
+                    // unlike the other mappers bank 0 can be mapped here
+                    0x4000..=0x7FFF => self.rom[(bank * ROM_BANK_SIZE + (loc & 0x3FFF)) % self.rom.len()],
+                    0xA000..=0xBFFF if enabled && !self.ram.is_empty() => {
+                        self.ram[Self::mbc5_ram_loc(loc, ram_bank, rumble) % self.ram.len()]
+                    }
+                    0xA000..=0xBFFF => 0xFF,
+                    _ => panic!("These read ranges should not be routed to cartridge")
+                }
+            }
         }
     }
     pub fn write(&mut self, loc: u16, val: u8) {
         match self.mapper {
             Mapper::ROM => (),
             Mapper::MBC1(ram, bank, hibank, simple) => {
-                let bankR = hibank * RAM_BANK_SIZE;
                 let nm = match loc {
                     0x0000..=0x1FFF => Mapper::MBC1(val & 0b1111 == 0xA, bank, hibank, simple),
                     0x2000..=0x3FFF => Mapper::MBC1(ram, (val & 0b11111) as usize, hibank, simple),
                     0x4000..=0x5FFF => Mapper::MBC1(ram, bank, (val & 0b11) as usize, simple),
                     0x6000..=0x7FFF => Mapper::MBC1(ram, bank, hibank, val & 0b1 != 0x1),
-                    0xA000..=0xBFFF if self.ramBanks > 1 => {
-                        let loc = loc as usize;
-                        self.ram[bankR + loc & 0x1FFF];
-                        Mapper::MBC1(ram, bank, hibank, val & 0b1 != 0x1)
+                    0xA000..=0xBFFF => {
+                        if ram && !self.ram.is_empty() {
+                            let l = self.mbc1_ram_loc(loc as usize, hibank, simple);
+                            self.ram[l] = val;
+                        }
+                        Mapper::MBC1(ram, bank, hibank, simple)
                     },
-                    0xA000..=0xBFFF => Mapper::MBC1(ram, bank, hibank, simple),
                     _ => panic!("This range should not be routed to cartridge: loc: 0x{:04X}", loc)
                 };
                 self.mapper = nm;
             }
+            Mapper::MBC2(ram, bank) => {
+                let nm = match loc {
+                    // Address bit 8 selects between ram enable and rom bank
+                    0x0000..=0x3FFF if loc & 0x100 == 0 => Mapper::MBC2(val & 0b1111 == 0xA, bank),
+                    0x0000..=0x3FFF => Mapper::MBC2(ram, if val & 0b1111 == 0 { 1 } else { (val & 0b1111) as usize }),
+                    0x4000..=0x7FFF => Mapper::MBC2(ram, bank),
+                    0xA000..=0xBFFF => {
+                        if ram {
+                            self.ram[loc as usize & 0x1FF] = val & 0b1111;
+                        }
+                        Mapper::MBC2(ram, bank)
+                    }
+                    _ => panic!("This range should not be routed to cartridge: loc: 0x{:04X}", loc)
+                };
+                self.mapper = nm;
+            }
+            Mapper::MBC3(enabled, bank, select, latch) => {
+                let nm = match loc {
+                    0x0000..=0x1FFF => Mapper::MBC3(val & 0b1111 == 0xA, bank, select, latch),
+                    0x2000..=0x3FFF => Mapper::MBC3(enabled, (val & 0x7F) as usize, select, latch),
+                    0x4000..=0x5FFF => Mapper::MBC3(enabled, bank, val, latch),
+                    0x6000..=0x7FFF => {
+                        // Writing 0 then 1 copies the running clock into the readable registers
+                        if latch == 0x00 && val == 0x01 {
+                            if let Some(rtc) = self.rtc.as_mut() {
+                                rtc.latch();
+                            }
+                        }
+                        Mapper::MBC3(enabled, bank, select, val)
+                    }
+                    0xA000..=0xBFFF => {
+                        if enabled {
+                            match select {
+                                0x00..=0x03 if !self.ram.is_empty() => {
+                                    let l = (select as usize * RAM_BANK_SIZE + (loc as usize & 0x1FFF)) % self.ram.len();
+                                    self.ram[l] = val;
+                                }
+                                0x08..=0x0C => {
+                                    if let Some(rtc) = self.rtc.as_mut() {
+                                        rtc.write(select, val);
+                                    }
+                                }
+                                _ => (),
+                            }
+                        }
+                        Mapper::MBC3(enabled, bank, select, latch)
+                    }
+                    _ => panic!("This range should not be routed to cartridge: loc: 0x{:04X}", loc)
+                };
+                self.mapper = nm;
+            }
+            Mapper::MBC5(enabled, bank, ram_bank, rumble) => {
+                let nm = match loc {
+                    0x0000..=0x1FFF => Mapper::MBC5(val & 0b1111 == 0xA, bank, ram_bank, rumble),
+                    // 9 bit rom bank, low 8 bits here and the 9th bit below
+                    0x2000..=0x2FFF => Mapper::MBC5(enabled, (bank & 0x100) | val as usize, ram_bank, rumble),
+                    0x3000..=0x3FFF => Mapper::MBC5(enabled, (bank & 0xFF) | ((val as usize & 0b1) << 8), ram_bank, rumble),
+                    0x4000..=0x5FFF => Mapper::MBC5(enabled, bank, val & 0b1111, rumble),
+                    0x6000..=0x7FFF => Mapper::MBC5(enabled, bank, ram_bank, rumble),
+                    0xA000..=0xBFFF => {
+                        if enabled && !self.ram.is_empty() {
+                            let l = Self::mbc5_ram_loc(loc as usize, ram_bank, rumble) % self.ram.len();
+                            self.ram[l] = val;
+                        }
+                        Mapper::MBC5(enabled, bank, ram_bank, rumble)
+                    }
+                    _ => panic!("This range should not be routed to cartridge: loc: 0x{:04X}", loc)
+                };
+                self.mapper = nm;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rtc::Clock;
+    use std::rc::Rc;
+
+    // Each rom bank is filled with its own bank number
+    fn create_test_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let banks = 2usize << rom_size;
+        let mut data = Vec::with_capacity(banks * ROM_BANK_SIZE);
+        for b in 0..banks {
+            data.extend(std::iter::repeat(b as u8).take(ROM_BANK_SIZE));
+        }
+        data[0x147] = cartridge_type;
+        data[0x148] = rom_size;
+        data[0x149] = ram_size;
+        data
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(Cartridge::from_data(vec![0; 0x100]).err(), Some(LoadError::Truncated(0x150, 0x100)));
+        assert_eq!(Cartridge::from_data(create_test_rom(0xFC, 0x00, 0x00)).err(), Some(LoadError::UnsupportedMapper(0xFC)));
+        assert_eq!(Cartridge::from_data(create_test_rom(0x00, 0x00, 0x09)).err(), Some(LoadError::InvalidRamSize(0x09)));
+
+        let mut data = create_test_rom(0x01, 0x00, 0x00);
+        data[0x148] = 0x20;
+        assert_eq!(Cartridge::from_data(data).err(), Some(LoadError::InvalidRomSize(0x20)));
+
+        let mut data = create_test_rom(0x01, 0x02, 0x00);
+        data.truncate(0x9000);
+        assert_eq!(Cartridge::from_data(data).err(), Some(LoadError::Truncated(0x20000, 0x9000)));
+    }
+
+    #[test]
+    fn test_rom_info() {
+        let mut data = create_test_rom(0x13, 0x02, 0x03);
+        data[0x134..0x13F].copy_from_slice(b"TESTGAME\0\0\0");
+        data[0x13F..0x143].copy_from_slice(b"ABCD");
+        data[0x143] = 0x80;
+        data[0x144..0x146].copy_from_slice(b"01");
+        data[0x146] = 0x03;
+        data[0x14A] = 0x01;
+        data[0x14B] = 0x33;
+        data[0x14C] = 0x02;
+        data[0x14D] = (0x134..=0x14C).fold(0u8, |x: u8, i| x.wrapping_sub(data[i]).wrapping_sub(1));
+        let sum = data.iter().fold(0u16, |x, b| x.wrapping_add(*b as u16));
+        data[0x14E] = (sum >> 8) as u8;
+        data[0x14F] = (sum & 0xFF) as u8;
+
+        let info = Cartridge::from_data(data.clone()).unwrap().rom_info().clone();
+        assert_eq!(info.title(), "TESTGAME");
+        assert_eq!(info.manufacturer_code(), "ABCD");
+        assert_eq!(info.supports_cgb(), true);
+        assert_eq!(info.cgb_only(), false);
+        assert_eq!(info.supports_sgb(), true);
+        assert_eq!(info.licensee_code(), "01");
+        assert_eq!(info.cartridge_type_name(), "MBC3+RAM+BATTERY");
+        assert_eq!(info.rom_size(), 128 * 1024);
+        assert_eq!(info.ram_size(), 32 * 1024);
+        assert_eq!(info.destination(), 1);
+        assert_eq!(info.version(), 2);
+        assert_eq!(info.header_checksum_valid(), true);
+        assert_eq!(info.global_checksum_valid(), true);
+
+        // A corrupted byte shows up in the global checksum only
+        data[0x4000] ^= 0xFF;
+        let info = Cartridge::from_data(data.clone()).unwrap().rom_info().clone();
+        assert_eq!(info.header_checksum_valid(), true);
+        assert_eq!(info.global_checksum_valid(), false);
+        data[0x134] = b'X';
+        let info = Cartridge::from_data(data).unwrap().rom_info().clone();
+        assert_eq!(info.header_checksum_valid(), false);
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x01, 0x05, 0x00)).unwrap(); // 1MB
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x2000, 0x00); // bank 0 maps to bank 1
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x2000, 0x13);
+        assert_eq!(cart.read(0x7FFF), 0x13);
+        cart.write(0x4000, 0x01);
+        assert_eq!(cart.read(0x4000), 0x33);
+        assert_eq!(cart.read(0x0000), 0x00);
+        cart.write(0x6000, 0x01); // advanced mode also banks 0x0000-0x3FFF
+        assert_eq!(cart.read(0x0000), 0x20);
+    }
+
+    #[test]
+    fn test_mbc2() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x06, 0x03, 0x00)).unwrap(); // 256KB
+        assert_eq!(cart.ram.len(), MBC2_RAM_SIZE);
+
+        // Bit 8 set selects the rom bank
+        cart.write(0x2100, 0x05);
+        assert_eq!(cart.read(0x4000), 0x05);
+        cart.write(0x0100, 0x00);
+        assert_eq!(cart.read(0x4000), 0x01);
+        // Bit 8 clear is ram enable and leaves the bank alone
+        cart.write(0x2000, 0x0A);
+        assert_eq!(cart.read(0x4000), 0x01);
+
+        cart.write(0xA000, 0xAB);
+        assert_eq!(cart.read(0xA000), 0xFB);
+        // Ram is echoed every 512 bytes
+        assert_eq!(cart.read(0xA200), 0xFB);
+        assert_eq!(cart.read(0xBE00), 0xFB);
+        cart.write(0xB1FF, 0x03);
+        assert_eq!(cart.read(0xA1FF), 0xF3);
+
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.read(0xA000), 0xFF);
+        cart.write(0xA000, 0x01);
+        cart.write(0x0000, 0x0A);
+        assert_eq!(cart.read(0xA000), 0xFB);
+    }
+
+    struct TestClock(Rc<std::cell::Cell<u64>>);
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_mbc3_rom_and_ram() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x13, 0x06, 0x03)).unwrap(); // 2MB
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x01);
+        cart.write(0x2000, 0x7F);
+        assert_eq!(cart.read(0x4000), 0x7F);
+        assert_eq!(cart.read(0x0000), 0x00);
+
+        cart.write(0xA000, 0x12);
+        assert_eq!(cart.read(0xA000), 0xFF);
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x03);
+        cart.write(0xA000, 0x12);
+        assert_eq!(cart.read(0xA000), 0x12);
+        cart.write(0x4000, 0x00);
+        assert_eq!(cart.read(0xA000), 0x00);
+        // No rtc on this cartridge type
+        cart.write(0x4000, 0x08);
+        assert_eq!(cart.read(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let time = Rc::new(std::cell::Cell::new(1000));
+        let mut cart = Cartridge::from_data(create_test_rom(0x10, 0x01, 0x03)).unwrap();
+        cart.set_rtc_clock(Rc::new(TestClock(time.clone())));
+        cart.write(0x0000, 0x0A);
+
+        let latch = |cart: &mut Cartridge| {
+            cart.write(0x6000, 0x00);
+            cart.write(0x6000, 0x01);
+        };
+        let read_rtc = |cart: &mut Cartridge, reg: u8| {
+            cart.write(0x4000, reg);
+            cart.read(0xA000)
+        };
+
+        time.set(1000 + 3 * 86400 + 5 * 3600 + 7 * 60 + 9);
+        // Registers only change on a latch
+        assert_eq!(read_rtc(&mut cart, 0x08), 0);
+        latch(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 9);
+        assert_eq!(read_rtc(&mut cart, 0x09), 7);
+        assert_eq!(read_rtc(&mut cart, 0x0A), 5);
+        assert_eq!(read_rtc(&mut cart, 0x0B), 3);
+        assert_eq!(read_rtc(&mut cart, 0x0C), 0);
+
+        // Writing 1 without a preceding 0 does not latch
+        time.set(time.get() + 1);
+        cart.write(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut cart, 0x08), 9);
+
+        // Halting stops the clock
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0b01000000);
+        time.set(time.get() + 100);
+        latch(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 10);
+        assert_eq!(read_rtc(&mut cart, 0x0C), 0b01000000);
+
+        // Day counter overflow sets the carry bit
+        cart.write(0x4000, 0x0B);
+        cart.write(0xA000, 0xFF);
+        cart.write(0x4000, 0x0C);
+        cart.write(0xA000, 0b00000001);
+        time.set(time.get() + 86400);
+        latch(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x0B), 0x00);
+        assert_eq!(read_rtc(&mut cart, 0x0C), 0b10000000);
+
+        // Time that passed on the old clock isn't counted when it's swapped out
+        time.set(time.get() + 100);
+        cart.set_rtc_clock(Rc::new(TestClock(Rc::new(std::cell::Cell::new(50)))));
+        latch(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 10);
+        assert_eq!(read_rtc(&mut cart, 0x0B), 0x00);
+
+        // Ram banks are still reachable
+        cart.write(0x4000, 0x01);
+        cart.write(0xA000, 0x42);
+        assert_eq!(cart.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_mbc5() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x1B, 0x08, 0x04)).unwrap(); // 8MB, 128KB ram
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x00);
+        cart.write(0x2000, 0x42);
+        assert_eq!(cart.read(0x4000), 0x42);
+        cart.rom[0x142 * ROM_BANK_SIZE] = 0xAB; // bank numbers only fit in a byte
+        cart.write(0x3000, 0x01);
+        assert_eq!(cart.read(0x4000), 0xAB);
+        cart.write(0x3000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x42);
+        cart.write(0x2000, 0xFF);
+        assert_eq!(cart.read(0x7FFF), 0xFF);
+
+        cart.write(0x0000, 0x0A);
+        for b in 0..16 {
+            cart.write(0x4000, b);
+            cart.write(0xA000, b + 0x10);
+        }
+        for b in 0..16 {
+            cart.write(0x4000, b);
+            assert_eq!(cart.read(0xA000), b + 0x10);
         }
+        assert_eq!(cart.is_rumbling(), false);
+    }
+
+    #[test]
+    fn test_mbc5_rumble() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x1E, 0x01, 0x03)).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x01);
+        cart.write(0xA000, 0x11);
+        assert_eq!(cart.is_rumbling(), false);
+
+        // The motor bit does not change the selected ram bank
+        cart.write(0x4000, 0x09);
+        assert_eq!(cart.is_rumbling(), true);
+        assert_eq!(cart.read(0xA000), 0x11);
+        cart.write(0x4000, 0x01);
+        assert_eq!(cart.is_rumbling(), false);
+    }
+
+    #[test]
+    fn test_save_ram() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x03, 0x01, 0x02)).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x12);
+        cart.write(0xBFFF, 0x34);
+        let save = cart.export_save_ram();
+        assert_eq!(save.len(), RAM_BANK_SIZE);
+
+        let mut cart = Cartridge::from_data(create_test_rom(0x03, 0x01, 0x02)).unwrap();
+        cart.import_save_ram(&save);
+        cart.write(0x0000, 0x0A);
+        assert_eq!(cart.read(0xA000), 0x12);
+        assert_eq!(cart.read(0xBFFF), 0x34);
+
+        // No battery, nothing to save
+        let cart = Cartridge::from_data(create_test_rom(0x02, 0x01, 0x02)).unwrap();
+        assert_eq!(cart.export_save_ram().len(), 0);
+    }
+
+    #[test]
+    fn test_save_ram_rtc() {
+        let time = Rc::new(std::cell::Cell::new(5000));
+        let mut cart = Cartridge::from_data(create_test_rom(0x10, 0x01, 0x02)).unwrap();
+        cart.set_rtc_clock(Rc::new(TestClock(time.clone())));
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x99);
+        time.set(5000 + 3600 + 2);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        let save = cart.export_save_ram();
+        assert_eq!(save.len(), RAM_BANK_SIZE + rtc::RTC_SAVE_SIZE);
+        assert_eq!(save[RAM_BANK_SIZE + 20], 2); // latched seconds
+        assert_eq!(save[RAM_BANK_SIZE + 28], 1); // latched hours
+
+        // Ten minutes pass while the game is off
+        time.set(time.get() + 600);
+        let mut cart = Cartridge::from_data(create_test_rom(0x10, 0x01, 0x02)).unwrap();
+        cart.set_rtc_clock(Rc::new(TestClock(time.clone())));
+        cart.import_save_ram(&save);
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x00);
+        assert_eq!(cart.read(0xA000), 0x99);
+        cart.write(0x4000, 0x08);
+        assert_eq!(cart.read(0xA000), 2);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        cart.write(0x4000, 0x09);
+        assert_eq!(cart.read(0xA000), 10);
+        cart.write(0x4000, 0x0A);
+        assert_eq!(cart.read(0xA000), 1);
+    }
+
+    #[test]
+    fn test_save_ram_2kb() {
+        // Only 2KB of the bank is on the cart, the rtc footer follows straight after
+        let time = Rc::new(std::cell::Cell::new(5000));
+        let mut cart = Cartridge::from_data(create_test_rom(0x10, 0x01, 0x01)).unwrap();
+        cart.set_rtc_clock(Rc::new(TestClock(time.clone())));
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA7FF, 0x56);
+        time.set(5000 + 3);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        let save = cart.export_save_ram();
+        assert_eq!(save.len(), 2 * 1024 + rtc::RTC_SAVE_SIZE);
+        assert_eq!(save[2 * 1024 - 1], 0x56);
+        assert_eq!(save[2 * 1024 + 20], 3); // latched seconds
+
+        let mut cart = Cartridge::from_data(create_test_rom(0x10, 0x01, 0x01)).unwrap();
+        cart.set_rtc_clock(Rc::new(TestClock(time.clone())));
+        cart.import_save_ram(&save);
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x00);
+        assert_eq!(cart.read(0xA7FF), 0x56);
+        cart.write(0x4000, 0x08);
+        assert_eq!(cart.read(0xA000), 3);
+    }
+
+    #[test]
+    fn test_mbc1_ram() {
+        let mut cart = Cartridge::from_data(create_test_rom(0x03, 0x01, 0x03)).unwrap(); // 32KB ram
+        assert_eq!(cart.ram.len(), 4 * RAM_BANK_SIZE);
+
+        // Disabled ram ignores writes and reads as open bus
+        cart.write(0xA000, 0x12);
+        assert_eq!(cart.read(0xA000), 0xFF);
+
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x12);
+        cart.write(0xBFFF, 0x34);
+        assert_eq!(cart.read(0xA000), 0x12);
+        assert_eq!(cart.read(0xBFFF), 0x34);
+
+        // In simple mode the bank register does not affect ram
+        cart.write(0x4000, 0x02);
+        assert_eq!(cart.read(0xA000), 0x12);
+
+        // In advanced mode it selects the ram bank
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0xA000), 0x00);
+        cart.write(0xA000, 0x56);
+        cart.write(0x6000, 0x00);
+        assert_eq!(cart.read(0xA000), 0x12);
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0xA000), 0x56);
+
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.read(0xA000), 0xFF);
     }
 }
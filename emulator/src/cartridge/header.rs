@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+// https://gbdev.io/pandocs/#the-cartridge-header
+// 0134-0143 Title (the last 5 bytes are reused by newer carts)
+// 013F-0142 Manufacturer Code
+// 0143      CGB Flag
+// 0144-0145 New Licensee Code
+// 0146      SGB Flag
+// 0147      Cartridge Type
+// 0148      ROM Size
+// 0149      RAM Size
+// 014A      Destination Code
+// 014B      Old Licensee Code
+// 014C      Mask ROM Version number
+// 014D      Header Checksum
+// 014E-014F Global Checksum
+pub const HEADER_END: usize = 0x150;
+
+#[wasm_bindgen]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RomInfo {
+    title: String,
+    manufacturer_code: String,
+    cgb_flag: u8,
+    sgb_flag: u8,
+    new_licensee_code: String,
+    old_licensee_code: u8,
+    cartridge_type: u8,
+    rom_size: usize, // in bytes
+    ram_size: usize, // in bytes
+    destination: u8,
+    version: u8,
+    header_checksum: u8,
+    header_checksum_valid: bool,
+    global_checksum: u16,
+    global_checksum_valid: bool,
+}
+
+// Reads as bytes past the end of a truncated dump as 0
+fn byte(data: &[u8], loc: usize) -> u8 {
+    data.get(loc).copied().unwrap_or(0)
+}
+
+fn ascii(data: &[u8], from: usize, to: usize) -> String {
+    (from..to)
+        .map(|i| byte(data, i))
+        .take_while(|b| *b != 0)
+        .map(|b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+impl RomInfo {
+    pub fn from_header(data: &[u8]) -> Self {
+        let cgb_flag = byte(data, 0x143);
+        // CGB carts took over the end of the title for the manufacturer code and cgb flag
+        let newer = cgb_flag & 0x80 != 0;
+        let title = ascii(data, 0x134, if newer { 0x13F } else { 0x144 });
+        let manufacturer_code = if newer && (0x13F..0x143).all(|i| byte(data, i).is_ascii_alphanumeric()) {
+            ascii(data, 0x13F, 0x143)
+        } else {
+            String::new()
+        };
+
+        let header_checksum = byte(data, 0x14D);
+        let computed_header = (0x134..=0x14C).fold(0u8, |x, i| x.wrapping_sub(byte(data, i)).wrapping_sub(1));
+
+        let global_checksum = (byte(data, 0x14E) as u16) << 8 | byte(data, 0x14F) as u16;
+        let computed_global = data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x14E && *i != 0x14F)
+            .fold(0u16, |x, (_, b)| x.wrapping_add(*b as u16));
+
+        Self {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            sgb_flag: byte(data, 0x146),
+            new_licensee_code: ascii(data, 0x144, 0x146),
+            old_licensee_code: byte(data, 0x14B),
+            cartridge_type: byte(data, 0x147),
+            rom_size: (32 * 1024) << byte(data, 0x148).min(8),
+            ram_size: match byte(data, 0x149) {
+                0x1 => 2 * 1024,
+                0x2 => 8 * 1024,
+                0x3 => 32 * 1024,
+                0x4 => 128 * 1024,
+                0x5 => 64 * 1024,
+                _ => 0,
+            },
+            destination: byte(data, 0x14A),
+            version: byte(data, 0x14C),
+            header_checksum,
+            header_checksum_valid: data.len() >= HEADER_END && header_checksum == computed_header,
+            global_checksum,
+            global_checksum_valid: data.len() >= HEADER_END && global_checksum == computed_global,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl RomInfo {
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.title.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn manufacturer_code(&self) -> String {
+        self.manufacturer_code.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn cgb_flag(&self) -> u8 {
+        self.cgb_flag
+    }
+    #[wasm_bindgen(getter)]
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+    #[wasm_bindgen(getter)]
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+    #[wasm_bindgen(getter)]
+    pub fn sgb_flag(&self) -> u8 {
+        self.sgb_flag
+    }
+    #[wasm_bindgen(getter)]
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+    #[wasm_bindgen(getter)]
+    pub fn new_licensee_code(&self) -> String {
+        self.new_licensee_code.clone()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn old_licensee_code(&self) -> u8 {
+        self.old_licensee_code
+    }
+    // 0x33 means the new licensee code is used instead
+    #[wasm_bindgen(getter)]
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+    #[wasm_bindgen(getter)]
+    pub fn cartridge_type(&self) -> u8 {
+        self.cartridge_type
+    }
+    #[wasm_bindgen(getter)]
+    pub fn cartridge_type_name(&self) -> String {
+        // https://gbdev.io/pandocs/#_0147-cartridge-type
+        match self.cartridge_type {
+            0x00 => "ROM ONLY",
+            0x01 => "MBC1",
+            0x02 => "MBC1+RAM",
+            0x03 => "MBC1+RAM+BATTERY",
+            0x05 => "MBC2",
+            0x06 => "MBC2+BATTERY",
+            0x08 => "ROM+RAM",
+            0x09 => "ROM+RAM+BATTERY",
+            0x0B => "MMM01",
+            0x0C => "MMM01+RAM",
+            0x0D => "MMM01+RAM+BATTERY",
+            0x0F => "MBC3+TIMER+BATTERY",
+            0x10 => "MBC3+TIMER+RAM+BATTERY",
+            0x11 => "MBC3",
+            0x12 => "MBC3+RAM",
+            0x13 => "MBC3+RAM+BATTERY",
+            0x19 => "MBC5",
+            0x1A => "MBC5+RAM",
+            0x1B => "MBC5+RAM+BATTERY",
+            0x1C => "MBC5+RUMBLE",
+            0x1D => "MBC5+RUMBLE+RAM",
+            0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+            0x20 => "MBC6",
+            0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            0xFC => "POCKET CAMERA",
+            0xFD => "BANDAI TAMA5",
+            0xFE => "HuC3",
+            0xFF => "HuC1+RAM+BATTERY",
+            _ => "UNKNOWN",
+        }
+        .to_string()
+    }
+    #[wasm_bindgen(getter)]
+    pub fn rom_size(&self) -> usize {
+        self.rom_size
+    }
+    #[wasm_bindgen(getter)]
+    pub fn ram_size(&self) -> usize {
+        self.ram_size
+    }
+    // 0 for Japanese carts, 1 for everywhere else
+    #[wasm_bindgen(getter)]
+    pub fn destination(&self) -> u8 {
+        self.destination
+    }
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+    #[wasm_bindgen(getter)]
+    pub fn header_checksum(&self) -> u8 {
+        self.header_checksum
+    }
+    // The boot rom refuses to start a cart with a bad header checksum
+    #[wasm_bindgen(getter)]
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum_valid
+    }
+    #[wasm_bindgen(getter)]
+    pub fn global_checksum(&self) -> u16 {
+        self.global_checksum
+    }
+    // Not checked by real hardware, but a mismatch usually means a bad dump
+    #[wasm_bindgen(getter)]
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum_valid
+    }
+}
+
+impl std::fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Title:     {} (v{})", self.title, self.version)?;
+        writeln!(
+            f,
+            "Type:      {} [{:02X}], ROM {}KB, RAM {}KB",
+            self.cartridge_type_name(),
+            self.cartridge_type,
+            self.rom_size / 1024,
+            self.ram_size / 1024
+        )?;
+        writeln!(
+            f,
+            "Licensee:  {}{}",
+            self.licensee_code(),
+            if self.manufacturer_code.is_empty() { String::new() } else { format!(", manufacturer {}", self.manufacturer_code) }
+        )?;
+        writeln!(
+            f,
+            "Hardware:  CGB {:02X}, SGB {:02X}, {}",
+            self.cgb_flag,
+            self.sgb_flag,
+            if self.destination == 0 { "Japan" } else { "Overseas" }
+        )?;
+        write!(
+            f,
+            "Checksums: header {:02X} ({}), global {:04X} ({})",
+            self.header_checksum,
+            if self.header_checksum_valid { "ok" } else { "BAD" },
+            self.global_checksum,
+            if self.global_checksum_valid { "ok" } else { "BAD" }
+        )
+    }
+}
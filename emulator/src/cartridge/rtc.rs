@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+
+// Source of wall clock time for the MBC3 real time clock, in seconds.
+// Swapping this out lets tests (or a frontend) control how time passes
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    // std::time is not available in the browser
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> u64 {
+        (js_date_now() / 1000.0) as u64
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn js_date_now() -> f64;
+}
+
+// Size of the rtc footer appended to .sav files, see https://bgb.bircd.org/rtcsave.html
+pub const RTC_SAVE_SIZE: usize = 48;
+
+pub fn system_clock() -> Rc<dyn Clock> {
+    Rc::new(SystemClock)
+}
+
+// https://gbdev.io/pandocs/#the-clock-counter-registers
+// 08h  RTC S   Seconds   0-59 (0-3Bh)
+// 09h  RTC M   Minutes   0-59 (0-3Bh)
+// 0Ah  RTC H   Hours     0-23 (0-17h)
+// 0Bh  RTC DL  Lower 8 bits of Day Counter (0-FFh)
+// 0Ch  RTC DH  Upper 1 bit of Day Counter, Carry Bit, Halt Flag
+//        Bit 0  Most significant bit of Day Counter (Bit 8)
+//        Bit 6  Halt (0=Active, 1=Stop Timer)
+//        Bit 7  Day Counter Carry Bit (1=Counter Overflow)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16, // 9 bits
+    pub halt: bool,
+    pub carry: bool,
+}
+
+impl RtcRegisters {
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => (self.days & 0xFF) as u8,
+            0x0C => {
+                ((self.days >> 8) as u8 & 0b1)
+                    | if self.halt { 0b01000000 } else { 0 }
+                    | if self.carry { 0b10000000 } else { 0 }
+            }
+            _ => panic!("Not an rtc register: {:02X}", reg),
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        match reg {
+            0x08 => self.seconds = val & 0b111111,
+            0x09 => self.minutes = val & 0b111111,
+            0x0A => self.hours = val & 0b11111,
+            0x0B => self.days = (self.days & 0x100) | val as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | ((val as u16 & 0b1) << 8);
+                self.halt = val & 0b01000000 != 0;
+                self.carry = val & 0b10000000 != 0;
+            }
+            _ => panic!("Not an rtc register: {:02X}", reg),
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halt || seconds == 0 {
+            return;
+        }
+        let mut t = self.seconds as u64 + seconds;
+        self.seconds = (t % 60) as u8;
+        t = t / 60 + self.minutes as u64;
+        self.minutes = (t % 60) as u8;
+        t = t / 60 + self.hours as u64;
+        self.hours = (t % 24) as u8;
+        t = t / 24 + self.days as u64;
+        if t > 0x1FF {
+            // The carry stays set until the game clears it
+            self.carry = true;
+        }
+        self.days = (t & 0x1FF) as u16;
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Rtc {
+    pub(crate) live: RtcRegisters,
+    pub(crate) latched: RtcRegisters,
+    pub(crate) last_time: u64, // clock time that live was last brought up to date at
+    #[serde(skip, default = "system_clock")]
+    clock: Rc<dyn Clock>,
+}
+
+impl Rtc {
+    pub fn new() -> Self {
+        Self::with_clock(system_clock())
+    }
+
+    pub fn with_clock(clock: Rc<dyn Clock>) -> Self {
+        Self {
+            live: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            last_time: clock.now(),
+            clock,
+        }
+    }
+
+    // The registers carry on from where they are, time on the old clock isn't counted
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.last_time = clock.now();
+        self.clock = clock;
+    }
+
+    fn update(&mut self) {
+        let now = self.clock.now();
+        self.live.advance(now.saturating_sub(self.last_time));
+        self.last_time = now;
+    }
+
+    pub fn latch(&mut self) {
+        self.update();
+        self.latched = self.live;
+    }
+
+    // Reads return the latched copy, which only changes on a latch
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched.read(reg)
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.update();
+        self.live.write(reg, val);
+    }
+
+    // Each of the 5 registers is stored as a little endian u32, first the
+    // running registers then the latched ones, followed by a u64 timestamp
+    pub fn to_save(&self) -> [u8; RTC_SAVE_SIZE] {
+        let mut data = [0u8; RTC_SAVE_SIZE];
+        for (i, reg) in (0x08..=0x0C).enumerate() {
+            data[i * 4] = self.live.read(reg);
+            data[20 + i * 4] = self.latched.read(reg);
+        }
+        data[40..48].copy_from_slice(&self.last_time.to_le_bytes());
+        data
+    }
+
+    // Also accepts the older 44 byte footer with a 32 bit timestamp
+    pub fn load_save(&mut self, data: &[u8]) {
+        if data.len() < 44 {
+            return;
+        }
+        for (i, reg) in (0x08..=0x0C).enumerate() {
+            self.live.write(reg, data[i * 4]);
+            self.latched.write(reg, data[20 + i * 4]);
+        }
+        let mut time = [0u8; 8];
+        let n = (data.len() - 40).min(8);
+        time[..n].copy_from_slice(&data[40..40 + n]);
+        self.last_time = u64::from_le_bytes(time);
+        // Catch up on the time that passed while the game was off
+        self.update();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::cpu_recievable::{CpuRecievable, CpuRecievable::*, Interrupt, Recievables};
 use crate::debugger::DebugOptions;
+use crate::gameboy::Model;
 use crate::instruction::{
     Instruction, JmpFlag, Jump, Location, Offset, Register16Loc, RegisterLoc,
 };
@@ -26,6 +27,9 @@ pub struct CPU {
     pub(crate) debug_options: DebugOptions,
     pub(crate) recievables: Recievables,
     pub(crate) ime: bool,
+    pub(crate) halted: bool,   // waiting in HALT for IE & IF to become non-zero
+    pub(crate) halt_bug: bool, // next opcode fetch does not increment pc
+    pub(crate) stopped: bool,  // waiting in STOP for a joypad line to go low
 }
 
 #[derive(Serialize, Deserialize)]
@@ -38,6 +42,9 @@ pub struct SaveState {
     debug_options: DebugOptions,
     recievables: Vec<CpuRecievable>,
     ime: bool,
+    halted: bool,
+    halt_bug: bool,
+    stopped: bool,
 }
 
 impl SaveState {
@@ -52,6 +59,9 @@ impl SaveState {
             debug_options: cpu.debug_options.clone(),
             recievables,
             ime: cpu.ime,
+            halted: cpu.halted,
+            halt_bug: cpu.halt_bug,
+            stopped: cpu.stopped,
         }
     }
 
@@ -71,6 +81,9 @@ impl SaveState {
             debug_options: self.debug_options.clone(),
             recievables,
             ime: self.ime,
+            halted: self.halted,
+            halt_bug: self.halt_bug,
+            stopped: self.stopped,
         }
     }
 }
@@ -94,6 +107,45 @@ impl CPU {
     pub fn get_canvas(&self) -> crate::ppu::Canvas {
         return self.bus.get_canvas();
     }
+    pub fn get_sgb_canvas(&self) -> Option<Vec<u32>> {
+        self.bus.get_sgb_canvas()
+    }
+    pub fn is_rumbling(&self) -> bool {
+        self.bus.is_rumbling()
+    }
+    pub fn model(&self) -> crate::gameboy::Model {
+        self.bus.model()
+    }
+    pub fn is_cgb_mode(&self) -> bool {
+        self.bus.is_cgb_mode()
+    }
+    pub fn is_double_speed(&self) -> bool {
+        self.bus.is_double_speed()
+    }
+    pub fn set_memory_blocking(&mut self, blocking: bool) {
+        self.bus.set_memory_blocking(blocking)
+    }
+    pub fn is_in_vblank(&self) -> bool {
+        self.bus.is_in_vblank()
+    }
+    pub fn set_serial_link(&mut self, link: Box<dyn crate::serial::SerialLink>) {
+        self.bus.set_serial_link(link)
+    }
+    pub fn take_serial_link(&mut self) -> Box<dyn crate::serial::SerialLink> {
+        self.bus.take_serial_link()
+    }
+    pub fn rom_info(&self) -> &crate::cartridge::RomInfo {
+        self.bus.rom_info()
+    }
+    pub fn has_battery(&self) -> bool {
+        self.bus.has_battery()
+    }
+    pub fn export_save_ram(&self) -> Vec<u8> {
+        self.bus.export_save_ram()
+    }
+    pub fn import_save_ram(&mut self, data: &[u8]) {
+        self.bus.import_save_ram(data)
+    }
 
     pub fn new(mut bus: crate::bus::Bus) -> Self {
         let recievables = Recievables::new();
@@ -107,6 +159,9 @@ impl CPU {
             debug_options: DebugOptions::default(),
             recievables,
             ime: true,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
         }
     }
 
@@ -117,10 +172,33 @@ impl CPU {
     pub fn post_bootrom(bus: crate::bus::Bus) -> Self {
         // https://gbdev.io/pandocs/#power-up-sequence
         let mut cpu = Self::new(bus);
-        cpu.set_af(0x01B0);
-        cpu.set_bc(0x0013);
-        cpu.set_de(0x00D8);
-        cpu.set_hl(0x014d);
+        match cpu.bus.model() {
+            Model::DMG => {
+                cpu.set_af(0x01B0);
+                cpu.set_bc(0x0013);
+                cpu.set_de(0x00D8);
+                cpu.set_hl(0x014d);
+            }
+            // Games check for A = 0x11 to detect they are running on a CGB
+            Model::CGB if cpu.bus.is_cgb_mode() => {
+                cpu.set_af(0x1180);
+                cpu.set_bc(0x0000);
+                cpu.set_de(0xFF56);
+                cpu.set_hl(0x000D);
+            }
+            Model::CGB => {
+                cpu.set_af(0x1180);
+                cpu.set_bc(0x0000);
+                cpu.set_de(0x0008);
+                cpu.set_hl(0x007C);
+            }
+            Model::SGB => {
+                cpu.set_af(0x0100);
+                cpu.set_bc(0x0014);
+                cpu.set_de(0x0000);
+                cpu.set_hl(0xC060);
+            }
+        }
         cpu.sp = 0xFFFE;
         cpu.pc = 0x100;
         cpu.write(0xFF05, 0x00); // TIMA
@@ -274,7 +352,7 @@ impl CPU {
     }
 
     pub fn update_joypad_register(&mut self, buttonmap: u8) {
-        self.bus.joypad.update_joypad(buttonmap);
+        self.bus.update_joypad(buttonmap);
     }
 
     fn get_register(&mut self, r: RegisterLoc) -> u8 {
@@ -336,9 +414,9 @@ impl CPU {
                     let x = match i {
                         Interrupt::VBlank => 0b1,
                         Interrupt::LCDStat => 0b10,
-                        Interrupt::Joypad => 0b100,
-                        Interrupt::Timer => 0b1000,
-                        Interrupt::Serial => 0b10000,
+                        Interrupt::Timer => 0b100,
+                        Interrupt::Serial => 0b1000,
+                        Interrupt::Joypad => 0b10000,
                     };
                     self.bus.reg_if.data |= x;
                 }
@@ -360,17 +438,21 @@ impl CPU {
         self.set_pc(loc as u16);
     }
 
+    fn pending_interrupts(&self) -> u8 {
+        self.bus.reg_if.data & self.bus.reg_ie.data & 0b11111
+    }
+
     fn interrupt(&mut self) {
         if self.ime && self.bus.reg_if.has_interrupts() {
             if self.bus.reg_if.get_vblank() && self.bus.reg_ie.get_vblank() {
                 self.perform_interrupt(0);
             } else if self.bus.reg_if.get_lcdstat() && self.bus.reg_ie.get_lcdstat() {
                 self.perform_interrupt(1);
-            } else if self.bus.reg_if.get_joypad() && self.bus.reg_ie.get_joypad() {
-                self.perform_interrupt(2);
             } else if self.bus.reg_if.get_timer() && self.bus.reg_ie.get_timer() {
-                self.perform_interrupt(3);
+                self.perform_interrupt(2);
             } else if self.bus.reg_if.get_serial() && self.bus.reg_ie.get_serial() {
+                self.perform_interrupt(3);
+            } else if self.bus.reg_if.get_joypad() && self.bus.reg_ie.get_joypad() {
                 self.perform_interrupt(4);
             }
         }
@@ -378,6 +460,28 @@ impl CPU {
 
     pub fn tick(&mut self) {
         self.process_recievables();
+        if self.stopped {
+            // https://gbdev.io/pandocs/#stop
+            // The system clock is stopped, so nothing else on the bus runs either
+            if !self.bus.joypad.any_line_low() {
+                return;
+            }
+            self.stopped = false;
+        }
+        if self.bus.is_hdma_transferring() {
+            self.clock();
+            return;
+        }
+        if self.halted {
+            // https://gbdev.io/pandocs/#halt
+            // The cpu stops fetching, but the rest of the system keeps running
+            if self.pending_interrupts() == 0 {
+                self.clock();
+                return;
+            }
+            self.halted = false;
+            self.bus.set_hdma_paused(false);
+        }
         self.interrupt();
         let instruction = self.next_op();
 //        println!("Instruction: {}", instruction);
@@ -452,10 +556,12 @@ impl CPU {
         );
         let c = self.cycles;
         let p = self.pc;
+        let hb = self.halt_bug;
         let instruction = self.next_op();
         let diff = self.pc - p;
         self.pc = p;
         self.cycles = c;
+        self.halt_bug = hb;
         let mut op0 = format!("  ");
         let mut op1 = format!("  ");
         let mut op2 = format!("  ");
@@ -496,7 +602,11 @@ impl CPU {
 
     fn next(&mut self) -> u8 {
         let data = self.read(self.pc);
-        self.pc = self.pc.wrapping_add(1);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
         return data;
     }
     fn next_signed(&mut self) -> i8 {
@@ -539,7 +649,10 @@ impl CPU {
 
             // Top Quarter ~ 0x00 - 0x3F
             0x00 => Instruction::Nop,
-            // 0X10 => TODO: STOP
+            0x10 => {
+                self.next(); // STOP is always followed by a padding byte
+                Instruction::Stop
+            } // STOP
             0x20 => Instruction::Jmp(Jump::Relative(self.next_signed()), Some(JmpFlag::NoZero)), // JR NZ, r8
             0x30 => Instruction::Jmp(Jump::Relative(self.next_signed()), Some(JmpFlag::NoCarry)), // JR NC, r8
 
@@ -879,7 +992,22 @@ impl CPU {
         }
 
         match op {
-            Instruction::Halt => self.print_state(),
+            Instruction::Halt => {
+                if !self.ime && self.pending_interrupts() != 0 {
+                    // DMG halt bug: halt is skipped and the next byte is read twice
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                    self.bus.set_hdma_paused(true);
+                }
+            }
+            Instruction::Stop => {
+                self.bus.write(0xFF04, 0); // DIV is reset when entering STOP
+                // On a CGB with a speed switch prepared, STOP switches speed and carries on
+                if !self.bus.switch_speed() {
+                    self.stopped = true;
+                }
+            }
             Instruction::Nop => (),
             Instruction::Load(dest, src) => {
                 let is16BitMode = isLoc16Bit(dest) || isLoc16Bit(src);
@@ -1470,6 +1598,33 @@ mod test {
         assert_eq!(test_cpu.cycles, 16 * 4);
     }
 
+    #[test]
+    fn test_stack_in_wram() {
+        let rom_data = vec![0xC5, 0xD1];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.set_register16(Register16Loc::BC, 0x1234);
+        test_cpu.sp = 0xD000;
+
+        // The stack is the same memory normal loads and stores see
+        test_cpu.tick();
+        assert_eq!(test_cpu.bus.read(0xCFFF), 0x12);
+        assert_eq!(test_cpu.bus.read(0xCFFE), 0x34);
+        test_cpu.bus.write(0xEFFF, 0x56); // echo ram
+        test_cpu.tick();
+        assert_eq!(test_cpu.get_register16(Register16Loc::DE), 0x5634);
+
+        // And follows the CGB work ram bank
+        let mut test_cpu = create_test_model_cpu(crate::gameboy::Model::CGB, 0x80);
+        test_cpu.sp = 0xE000;
+        test_cpu.write(0xFF70, 2);
+        test_cpu.stack_push(0xABCD);
+        test_cpu.write(0xFF70, 3);
+        assert_eq!(test_cpu.read(0xDFFF), 0x00);
+        test_cpu.write(0xFF70, 2);
+        assert_eq!(test_cpu.read(0xDFFF), 0xAB);
+        assert_eq!(test_cpu.stack_pop(), 0xABCD);
+    }
+
     #[test]
     fn test_pop() {
         let rom_data = vec![0xC1, 0xD1, 0xE1, 0xF1];
@@ -2664,4 +2819,419 @@ mod test {
         assert_eq!(test_cpu.get_flag(Flag::HalfCarry), false);
         assert_eq!(test_cpu.get_flag(Flag::Carry), true);
     }
+
+    #[test]
+    fn test_halt() {
+        let rom_data = vec![0x76, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = false;
+
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+        assert_eq!(test_cpu.pc, 0x0001);
+        assert_eq!(test_cpu.cycles, 4);
+
+        // Stays halted and keeps clocking while nothing is pending
+        for _ in 0..10 {
+            test_cpu.tick();
+        }
+        assert_eq!(test_cpu.halted, true);
+        assert_eq!(test_cpu.pc, 0x0001);
+        assert_eq!(test_cpu.cycles, 44);
+
+        // Requested but not enabled interrupts do not wake the cpu
+        test_cpu.bus.reg_if.data = 0b100;
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+
+        // With IME clear the cpu wakes and continues without servicing
+        test_cpu.bus.reg_ie.data = 0b100;
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, false);
+        assert_eq!(test_cpu.pc, 0x0002);
+        assert_eq!(test_cpu.bus.reg_if.data, 0b100);
+    }
+
+    #[test]
+    fn test_halt_interrupt() {
+        let rom_data = vec![0x76, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = true;
+        test_cpu.sp = 0xFFFE;
+
+        test_cpu.tick();
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+
+        // With IME set the interrupt is serviced on wake
+        test_cpu.bus.reg_ie.data = 0b1;
+        test_cpu.bus.reg_if.data = 0b1;
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, false);
+        assert_eq!(test_cpu.bus.reg_if.data, 0b0);
+        assert_eq!(test_cpu.sp, 0xFFFC);
+        assert_eq!(test_cpu.bus.stack_pop(0xFFFD), 0x00);
+        assert_eq!(test_cpu.bus.stack_pop(0xFFFC), 0x01);
+        // The handler at 0x40 has started executing
+        assert_eq!(test_cpu.pc, 0x0041);
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        let rom_data = vec![0x76, 0x3C, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = false;
+        test_cpu.set_register(RegisterLoc::A, 0x00);
+        test_cpu.bus.reg_ie.data = 0b1000;
+        test_cpu.bus.reg_if.data = 0b1000;
+
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, false);
+        assert_eq!(test_cpu.pc, 0x0001);
+
+        // INC A is fetched without incrementing pc, so it runs twice
+        test_cpu.tick();
+        assert_eq!(test_cpu.pc, 0x0001);
+        assert_eq!(test_cpu.get_register(RegisterLoc::A), 0x01);
+        test_cpu.tick();
+        assert_eq!(test_cpu.pc, 0x0002);
+        assert_eq!(test_cpu.get_register(RegisterLoc::A), 0x02);
+    }
+
+    #[test]
+    fn test_stop() {
+        let rom_data = vec![0x10, 0x00, 0x3C];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.set_register(RegisterLoc::A, 0x00);
+        test_cpu.bus.joypad.write(0b100000); // select direction keys
+
+        test_cpu.tick();
+        assert_eq!(test_cpu.stopped, true);
+        assert_eq!(test_cpu.pc, 0x0002);
+        assert_eq!(test_cpu.cycles, 8);
+        assert_eq!(test_cpu.bus.read(0xFF04), 0x00);
+
+        // Nothing is clocked while stopped
+        for _ in 0..1000 {
+            test_cpu.tick();
+        }
+        assert_eq!(test_cpu.stopped, true);
+        assert_eq!(test_cpu.pc, 0x0002);
+        assert_eq!(test_cpu.cycles, 8);
+        assert_eq!(test_cpu.bus.read(0xFF04), 0x00);
+
+        // A selected button going low wakes the cpu
+        test_cpu.update_joypad_register(crate::gameboy::BUT_RIGHT);
+        test_cpu.tick();
+        assert_eq!(test_cpu.stopped, false);
+        assert_eq!(test_cpu.pc, 0x0003);
+        assert_eq!(test_cpu.get_register(RegisterLoc::A), 0x01);
+    }
+
+    #[test]
+    fn test_joypad_interrupt() {
+        // https://gbdev.io/pandocs/#int-60-joypad-interrupt
+        let rom_data = vec![0x76, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = false;
+        test_cpu.bus.reg_ie.data = 0b10000;
+        test_cpu.bus.write(0xFF00, 0b100000); // select direction keys
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+
+        // Buttons that aren't selected don't pull a line low
+        test_cpu.update_joypad_register(crate::gameboy::BUT_A);
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+
+        // A selected button going low requests the interrupt and wakes the cpu
+        test_cpu.update_joypad_register(crate::gameboy::BUT_A | crate::gameboy::BUT_RIGHT);
+        assert_eq!(test_cpu.bus.reg_if.data, 0b10000);
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, false);
+
+        // Lines that are already low, or go back high, don't request it again
+        test_cpu.bus.reg_if.data = 0;
+        test_cpu.update_joypad_register(crate::gameboy::BUT_RIGHT);
+        test_cpu.update_joypad_register(0);
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+
+        // Selecting a group with a button held also pulls a line low
+        test_cpu.update_joypad_register(crate::gameboy::BUT_A);
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+        test_cpu.bus.write(0xFF00, 0b010000); // select action keys
+        assert_eq!(test_cpu.bus.reg_if.data, 0b10000);
+
+        // With IME set it's serviced at 0x60
+        test_cpu.bus.reg_if.data = 0;
+        test_cpu.update_joypad_register(0);
+        test_cpu.ime = true;
+        test_cpu.sp = 0xFFFE;
+        test_cpu.update_joypad_register(crate::gameboy::BUT_A);
+        test_cpu.tick();
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+        assert_eq!(test_cpu.pc, 0x0061);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        // https://gbdev.io/pandocs/#int-50-timer-interrupt
+        let rom_data = vec![0x76, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = true;
+        test_cpu.sp = 0xFFFE;
+        test_cpu.bus.reg_ie.data = 0b100;
+        test_cpu.bus.write(0xFF05, 0xFF); // TIMA
+        test_cpu.bus.write(0xFF07, 0b101); // every 4 cycles
+
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+        for _ in 0..10 {
+            if !test_cpu.halted {
+                break;
+            }
+            test_cpu.tick();
+        }
+        assert_eq!(test_cpu.halted, false);
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+        // The handler at 0x50 has started executing
+        assert_eq!(test_cpu.pc, 0x0051);
+    }
+
+    #[test]
+    fn test_serial_interrupt() {
+        // https://gbdev.io/pandocs/#int-58-serial-interrupt
+        let rom_data = vec![0x76, 0x00];
+        let mut test_cpu = create_test_cpu(rom_data);
+        test_cpu.ime = true;
+        test_cpu.sp = 0xFFFE;
+        test_cpu.bus.reg_ie.data = 0b1000;
+        test_cpu.bus.write(0xFF01, 0x42); // SB
+        test_cpu.bus.write(0xFF02, 0x81); // start with the internal clock
+
+        test_cpu.tick();
+        assert_eq!(test_cpu.halted, true);
+        for _ in 0..2000 {
+            if !test_cpu.halted {
+                break;
+            }
+            test_cpu.tick();
+        }
+        assert_eq!(test_cpu.halted, false);
+        assert_eq!(test_cpu.bus.reg_if.data, 0);
+        // Nothing is plugged in, so all 1s were shifted in
+        assert_eq!(test_cpu.bus.read(0xFF01), 0xFF);
+        // The handler at 0x58 has started executing
+        assert_eq!(test_cpu.pc, 0x0059);
+    }
+
+    fn create_test_model_cpu(model: crate::gameboy::Model, cgb_flag: u8) -> CPU {
+        let mut rom_data = vec![0; 0x8000];
+        rom_data[0x143] = cgb_flag;
+        let mut bus = Bus::new(Cartridge::test(rom_data));
+        bus.set_model(model);
+        CPU::post_bootrom(bus)
+    }
+
+    #[test]
+    fn test_cgb_mode() {
+        use crate::gameboy::Model;
+        let dmg = create_test_model_cpu(Model::DMG, 0x80);
+        assert_eq!(dmg.is_cgb_mode(), false);
+        assert_eq!(dmg.a(), 0x01);
+
+        let mut test_cpu = create_test_model_cpu(Model::CGB, 0x80);
+        assert_eq!(test_cpu.is_cgb_mode(), true);
+        assert_eq!(test_cpu.a(), 0x11);
+
+        // Work ram banks 1-7 at 0xD000, with 0 selecting bank 1
+        test_cpu.write(0xFF70, 2);
+        assert_eq!(test_cpu.read(0xFF70), 0xFA);
+        test_cpu.write(0xD000, 0xAA);
+        test_cpu.write(0xFF70, 3);
+        assert_eq!(test_cpu.read(0xD000), 0x00);
+        test_cpu.write(0xFF70, 0);
+        test_cpu.write(0xD000, 0xBB);
+        test_cpu.write(0xFF70, 1);
+        assert_eq!(test_cpu.read(0xD000), 0xBB);
+        test_cpu.write(0xFF70, 2);
+        assert_eq!(test_cpu.read(0xD000), 0xAA);
+        assert_eq!(test_cpu.read(0xF000), 0xAA);
+
+        // Vram bank 1
+        test_cpu.write(0xFF40, 0); // lcd off, so vram isn't blocked by the ppu
+        test_cpu.write(0xFF4F, 1);
+        assert_eq!(test_cpu.read(0xFF4F), 0xFF);
+        test_cpu.write(0x8000, 0x55);
+        test_cpu.write(0xFF4F, 0);
+        assert_eq!(test_cpu.read(0xFF4F), 0xFE);
+        assert_eq!(test_cpu.read(0x8000), 0x00);
+
+        // A DMG cart on a CGB runs in compatibility mode with the banks locked
+        let mut compat = create_test_model_cpu(Model::CGB, 0x00);
+        assert_eq!(compat.is_cgb_mode(), false);
+        assert_eq!(compat.a(), 0x11);
+        compat.write(0xD000, 0xAA);
+        compat.write(0xFF70, 2);
+        assert_eq!(compat.read(0xFF70), 0xFF);
+        assert_eq!(compat.read(0xD000), 0xAA);
+        compat.write(0xFF4F, 1);
+        assert_eq!(compat.read(0xFF4F), 0xFF);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        use crate::gameboy::Model;
+        let mut test_cpu = create_test_model_cpu(Model::CGB, 0x80);
+        // ld a, 1; ldh (0x4D), a; stop
+        test_cpu.bus.write(0xC000, 0x3E);
+        test_cpu.bus.write(0xC001, 0x01);
+        test_cpu.bus.write(0xC002, 0xE0);
+        test_cpu.bus.write(0xC003, 0x4D);
+        test_cpu.bus.write(0xC004, 0x10);
+        test_cpu.bus.write(0xC005, 0x00);
+        test_cpu.pc = 0xC000;
+        assert_eq!(test_cpu.read(0xFF4D), 0x7E);
+        test_cpu.tick();
+        test_cpu.tick();
+        assert_eq!(test_cpu.read(0xFF4D), 0x7F);
+        test_cpu.tick();
+        assert_eq!(test_cpu.stopped, false);
+        assert_eq!(test_cpu.is_double_speed(), true);
+        assert_eq!(test_cpu.read(0xFF4D), 0xFE);
+        assert_eq!(test_cpu.pc, 0xC006);
+
+        // The ppu only gets two dots per cpu cycle now
+        let ly = test_cpu.bus.read(0xFF44);
+        while test_cpu.bus.read(0xFF44) == ly {
+            test_cpu.clock();
+        }
+        // 200 cycles would be more than a line at normal speed
+        let ly = test_cpu.bus.read(0xFF44);
+        for _ in 0..200 {
+            test_cpu.clock();
+        }
+        assert_eq!(test_cpu.bus.read(0xFF44), ly);
+
+        // Without a switch prepared STOP stops as normal
+        test_cpu.pc = 0xC004;
+        test_cpu.tick();
+        assert_eq!(test_cpu.stopped, true);
+        assert_eq!(test_cpu.is_double_speed(), true);
+
+        // KEY1 does nothing outside of CGB mode
+        let mut dmg = create_test_model_cpu(Model::DMG, 0x80);
+        dmg.write(0xFF4D, 0x01);
+        assert_eq!(dmg.read(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn test_gdma() {
+        use crate::gameboy::Model;
+        let mut test_cpu = create_test_model_cpu(Model::CGB, 0x80);
+        test_cpu.write(0xFF40, 0); // lcd off, so vram isn't blocked by the ppu
+        for i in 0..0x40 {
+            test_cpu.bus.write(0xC000 + i, i as u8 + 1);
+        }
+        test_cpu.bus.write(0xFF51, 0xC0);
+        test_cpu.bus.write(0xFF52, 0x0F); // lower bits are ignored
+        test_cpu.bus.write(0xFF53, 0xE1); // only bits 0-4 are used
+        test_cpu.bus.write(0xFF54, 0x00);
+        test_cpu.bus.write(0xFF55, 0x01); // 2 blocks
+        assert_eq!(test_cpu.bus.is_hdma_transferring(), true);
+
+        // The cpu is stalled for 8 cycles per block
+        test_cpu.pc = 0xC000;
+        let cycles = test_cpu.cycles;
+        while test_cpu.bus.is_hdma_transferring() {
+            test_cpu.tick();
+        }
+        assert_eq!(test_cpu.pc, 0xC000);
+        assert_eq!(test_cpu.cycles - cycles, 16 * 4);
+        assert_eq!(test_cpu.bus.read(0xFF55), 0xFF);
+        for i in 0..0x20 {
+            assert_eq!(test_cpu.bus.read(0x8100 + i), i as u8 + 1);
+        }
+        assert_eq!(test_cpu.bus.read(0x8120), 0x00);
+    }
+
+    // https://gbdev.io/pandocs/#oam-dma-transfer
+    #[test]
+    fn test_oam_dma_blocking() {
+        use crate::gameboy::Model;
+        let mut test_cpu = create_test_model_cpu(Model::DMG, 0);
+        test_cpu.write(0xFF40, 0); // lcd off, so OAM isn't blocked by the ppu
+        for i in 0..0xA0 {
+            test_cpu.write(0xC000 + i, i as u8 + 1);
+        }
+        test_cpu.write(0xFF46, 0xC0);
+
+        // Only HRAM and the io registers can be used during the transfer
+        assert_eq!(test_cpu.read(0xC000), 0xFF);
+        test_cpu.write(0xC000, 0xAA);
+        test_cpu.write(0xFF80, 0xBB);
+        assert_eq!(test_cpu.read(0xFF80), 0xBB);
+        assert_eq!(test_cpu.read(0xFF46), 0xC0);
+
+        // It can be turned off for debugging
+        test_cpu.set_memory_blocking(false);
+        assert_eq!(test_cpu.read(0xC000), 0x01);
+        test_cpu.set_memory_blocking(true);
+
+        for _ in 0..161 { // 1 byte per cycle, then one more to finish
+            test_cpu.clock();
+        }
+        assert_eq!(test_cpu.read(0xC000), 0x01);
+        for i in 0..0xA0 {
+            assert_eq!(test_cpu.read(0xFE00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_hdma() {
+        use crate::gameboy::Model;
+        let mut test_cpu = create_test_model_cpu(Model::CGB, 0x80);
+        for i in 0..0x40 {
+            test_cpu.bus.write(0xC000 + i, i as u8 + 1);
+        }
+        let wait_hblanks = |cpu: &mut CPU, n: usize| {
+            for _ in 0..n {
+                while cpu.bus.read(0xFF41) & 0b11 == 0 {
+                    cpu.clock();
+                }
+                while cpu.bus.read(0xFF41) & 0b11 != 0 || cpu.bus.read(0xFF44) >= 144 {
+                    cpu.clock();
+                }
+                while cpu.bus.is_hdma_transferring() {
+                    cpu.clock();
+                }
+            }
+        };
+        test_cpu.bus.write(0xFF51, 0xC0);
+        test_cpu.bus.write(0xFF52, 0x00);
+        test_cpu.bus.write(0xFF53, 0x00);
+        test_cpu.bus.write(0xFF54, 0x00);
+        test_cpu.bus.write(0xFF55, 0x82); // 3 blocks in H-Blank mode
+        assert_eq!(test_cpu.bus.is_hdma_transferring(), false);
+        assert_eq!(test_cpu.bus.read(0xFF55), 0x02);
+
+        // One block per H-Blank
+        wait_hblanks(&mut test_cpu, 1);
+        assert_eq!(test_cpu.bus.read(0xFF55), 0x01);
+        assert_eq!(test_cpu.bus.read(0x800F), 0x10);
+        assert_eq!(test_cpu.bus.read(0x8010), 0x00);
+
+        // Nothing is copied while halted
+        test_cpu.bus.set_hdma_paused(true);
+        wait_hblanks(&mut test_cpu, 1);
+        assert_eq!(test_cpu.bus.read(0xFF55), 0x01);
+        test_cpu.bus.set_hdma_paused(false);
+
+        // Cancelling leaves bit 7 set with the blocks left
+        wait_hblanks(&mut test_cpu, 1);
+        assert_eq!(test_cpu.bus.read(0x801F), 0x20);
+        test_cpu.bus.write(0xFF55, 0x00);
+        assert_eq!(test_cpu.bus.read(0xFF55), 0x80);
+        wait_hblanks(&mut test_cpu, 1);
+        assert_eq!(test_cpu.bus.read(0x8020), 0x00);
+    }
 }
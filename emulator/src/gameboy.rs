@@ -1,5 +1,6 @@
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, LoadError};
 use crate::cpu::CPU;
+use serde::{Deserialize, Serialize};
 
 pub const BUT_START: u8 = 0b1;
 pub const BUT_SELECT: u8 = 0b10;
@@ -10,14 +11,26 @@ pub const BUT_RIGHT: u8 = 0b100000;
 pub const BUT_A: u8 = 0b1000000;
 pub const BUT_B: u8 = 0b10000000;
 
+// The hardware being emulated. A CGB only runs in CGB mode for carts that
+// support it, everything else gets the DMG compatible mode
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Model {
+    DMG,
+    CGB,
+    SGB,
+}
+
 pub struct GameboyBuilder {
     rom: Option<Cartridge>,
     bios: Option<Vec<u8>>,
+    model: Model,
+    memory_blocking: bool,
 }
 
 pub struct Gameboy {
     pub(crate) cpu: CPU,
     buttons_pressed: u8,
+    pending_input: Option<u8>, // from set_input, waiting for the next vblank
 }
 
 impl GameboyBuilder {
@@ -25,6 +38,8 @@ impl GameboyBuilder {
         return Self {
             rom: None,
             bios: None,
+            model: Model::DMG,
+            memory_blocking: true,
         };
     }
 
@@ -38,30 +53,60 @@ impl GameboyBuilder {
         self
     }
 
-    pub fn build(&self) -> Gameboy {
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = model;
+        self
+    }
+
+    // Turning this off lets the cpu access vram and OAM at any time, which is
+    // handy for debugging homebrew but isn't what the hardware does
+    pub fn memory_blocking(mut self, enabled: bool) -> Self {
+        self.memory_blocking = enabled;
+        self
+    }
+
+    pub fn build(&self) -> Result<Gameboy, LoadError> {
         if let Some(rom) = self.rom.clone() {
             if let Some(bios) = &self.bios {
-                return Gameboy {
-                    cpu: CPU::with_bios(crate::bus::Bus::with_bios(rom, bios.clone())),
+                let mut bus = crate::bus::Bus::with_bios(rom, bios.clone());
+                bus.set_model(self.model);
+                bus.set_memory_blocking(self.memory_blocking);
+                return Ok(Gameboy {
+                    cpu: CPU::with_bios(bus),
                     buttons_pressed: 0,
-                };
+                    pending_input: None,
+                });
             } else {
-                return Gameboy {
-                    cpu: CPU::post_bootrom(crate::bus::Bus::new(rom)),
+                let mut bus = crate::bus::Bus::new(rom);
+                bus.set_model(self.model);
+                bus.set_memory_blocking(self.memory_blocking);
+                return Ok(Gameboy {
+                    cpu: CPU::post_bootrom(bus),
                     buttons_pressed: 0,
-                };
+                    pending_input: None,
+                });
             }
         }
-        panic!("Builder not fully initialized")
+        Err(LoadError::NoRom)
     }
 }
 
 impl Gameboy {
+    // The link cable stays plugged in across state changes
     pub fn set_state(&mut self, new_state: Gameboy) {
+        let link = self.cpu.take_serial_link();
         self.cpu = new_state.cpu;
+        self.cpu.set_serial_link(link);
     }
 
     pub fn tick(&mut self) {
+        if let Some(input) = self.pending_input {
+            if self.cpu.is_in_vblank() {
+                self.pending_input = None;
+                self.buttons_pressed = input;
+                self.cpu.update_joypad_register(input)
+            }
+        }
         self.cpu.tick()
     }
     pub fn get_screen(&self) -> &crate::ppu::Screen {
@@ -70,6 +115,48 @@ impl Gameboy {
     pub fn get_canvas(&self) -> crate::ppu::Canvas {
         return self.cpu.get_canvas();
     }
+    // The SGB_WIDTH x SGB_HEIGHT frame with the border, None if this isn't an SGB
+    pub fn get_sgb_canvas(&self) -> Option<Vec<u32>> {
+        self.cpu.get_sgb_canvas()
+    }
+    pub fn is_rumbling(&self) -> bool {
+        self.cpu.is_rumbling()
+    }
+    pub fn model(&self) -> Model {
+        self.cpu.model()
+    }
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cpu.is_cgb_mode()
+    }
+    // In double speed twice as many ticks are needed per frame
+    pub fn is_double_speed(&self) -> bool {
+        self.cpu.is_double_speed()
+    }
+    pub fn set_memory_blocking(&mut self, blocking: bool) {
+        self.cpu.set_memory_blocking(blocking)
+    }
+    // Plugs something into the link port, replacing whatever was there
+    pub fn set_serial_link(&mut self, link: Box<dyn crate::serial::SerialLink>) {
+        self.cpu.set_serial_link(link)
+    }
+    // Connects the link ports of two gameboys ticked from the same thread
+    pub fn link_with(&mut self, other: &mut Gameboy) {
+        let (a, b) = crate::serial::link_pair();
+        self.set_serial_link(Box::new(a));
+        other.set_serial_link(Box::new(b));
+    }
+    pub fn rom_info(&self) -> &crate::cartridge::RomInfo {
+        self.cpu.rom_info()
+    }
+    pub fn has_battery(&self) -> bool {
+        self.cpu.has_battery()
+    }
+    pub fn export_save_ram(&self) -> Vec<u8> {
+        self.cpu.export_save_ram()
+    }
+    pub fn import_save_ram(&mut self, data: &[u8]) {
+        self.cpu.import_save_ram(data)
+    }
 
     pub fn get_audio_buffer(&self) -> [f32; crate::apu::SAMPLE_SIZE] {
         self.cpu.get_audio_buffer()
@@ -100,7 +187,9 @@ impl Gameboy {
     }
 
     pub fn load(&mut self, save: &crate::cpu::SaveState) {
-        self.cpu = save.load()
+        let link = self.cpu.take_serial_link();
+        self.cpu = save.load();
+        self.cpu.set_serial_link(link);
     }
 
     pub fn button_down(&mut self, button: u8) {
@@ -112,4 +201,45 @@ impl Gameboy {
         self.buttons_pressed &= !button;
         self.cpu.update_joypad_register(self.buttons_pressed)
     }
+
+    // Replaces every button at once with the BUT_* bitmap in state. It's held until the
+    // next vblank so the game sees the whole change between two frames
+    pub fn set_input(&mut self, state: u8) {
+        self.pending_input = Some(state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_input() {
+        let mut rom_data = vec![0; 0x8000];
+        rom_data[0x100] = 0x18; // JR -2
+        rom_data[0x101] = 0xFE;
+        let rom = Cartridge::test(rom_data);
+        let mut gameboy = GameboyBuilder::new().load_rom(rom).build().unwrap();
+        gameboy.cpu.bus.write(0xFF00, 0b100000); // select direction keys
+        while gameboy.cpu.is_in_vblank() {
+            gameboy.tick();
+        }
+
+        // Nothing changes until the frame is done
+        gameboy.set_input(BUT_RIGHT | BUT_UP);
+        for _ in 0..100 {
+            gameboy.tick();
+        }
+        assert_eq!(gameboy.cpu.bus.read(0xFF00) & 0b1111, 0b1111);
+        while !gameboy.cpu.is_in_vblank() {
+            gameboy.tick();
+        }
+        gameboy.tick();
+        assert_eq!(gameboy.cpu.bus.read(0xFF00) & 0b1111, 0b1010);
+        assert_eq!(gameboy.cpu.bus.reg_if.data & 0b10000, 0b10000);
+
+        // button_down and button_up carry on from there
+        gameboy.button_up(BUT_UP);
+        assert_eq!(gameboy.cpu.bus.read(0xFF00) & 0b1111, 0b1110);
+    }
 }
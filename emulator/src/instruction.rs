@@ -111,6 +111,7 @@ pub enum Instruction {
     DI,
     EI,
     Halt,
+    Stop,
     Nop
 }
 
@@ -274,6 +275,7 @@ impl Display for Instruction {
                 }
             }
             Self::Halt  => ("HALT", String::new()),
+            Self::Stop  => ("STOP", String::new()),
         };
         write!(f, "{}{}", op, args)
     }
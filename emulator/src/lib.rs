@@ -7,68 +7,169 @@ mod debugger;
 mod gameboy;
 mod instruction;
 mod ppu;
+mod printer;
+mod serial;
+mod sgb;
 mod timer;
 mod utils;
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
-// TODO: Maybe don't use a static mut, and instead pass a reference to the
-// frontend for the gb object, and just get that back each update call
-static mut GAMEBOY: Option<gameboy::Gameboy> = None;
+fn not_initialized() -> JsValue {
+    JsValue::from_str("Emulator has not been initialized with a rom")
+}
 
+// A handle to one gameboy, so the frontend can run as many as it likes
 #[wasm_bindgen]
-pub fn init(romdata: Vec<u8>) {
-    utils::set_panic_hook();
-    let gameboy = gameboy::GameboyBuilder::new()
-        .load_rom(cartridge::Cartridge::from_data(romdata))
-        .build();
-    unsafe { GAMEBOY = Some(gameboy) }
+pub struct Emulator {
+    gameboy: Option<gameboy::Gameboy>,
+    printed: Rc<RefCell<VecDeque<printer::PrintedImage>>>,
 }
 
-#[wasm_bindgen]
-pub fn update(x: usize) -> Vec<u32> {
-    for _ in 0..x {
-        unsafe {
-            GAMEBOY.as_mut().unwrap().tick();
-        }
+impl Emulator {
+    fn gameboy(&self) -> Result<&gameboy::Gameboy, JsValue> {
+        self.gameboy.as_ref().ok_or_else(not_initialized)
     }
-    unsafe { GAMEBOY.as_mut().unwrap().get_canvas().to_vec() }
-}
 
-#[wasm_bindgen]
-pub fn get_audio_buffer() -> Vec<f32> {
-    unsafe { GAMEBOY.as_mut().unwrap().get_audio_buffer().to_vec() }
+    fn gameboy_mut(&mut self) -> Result<&mut gameboy::Gameboy, JsValue> {
+        self.gameboy.as_mut().ok_or_else(not_initialized)
+    }
 }
 
-#[wasm_bindgen]
-pub fn button_down(b: isize) {
-    let bt = 1 << b; // ensure Emulator.ts and gameboy.rs have buttons in the same order
-    unsafe { GAMEBOY.as_mut().unwrap().button_down(bt) }
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
-pub fn button_up(b: isize) {
-    let bt = 1 << b; // ensure Emulator.ts and gameboy.rs have buttons in the same order
-    unsafe { GAMEBOY.as_mut().unwrap().button_up(bt) }
-}
+impl Emulator {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        utils::set_panic_hook();
+        Self {
+            gameboy: None,
+            printed: Rc::new(RefCell::new(VecDeque::new())),
+        }
+    }
 
-#[wasm_bindgen]
-pub fn save_state() -> Vec<u8> {
-    unsafe {
-        let state = GAMEBOY.as_mut().unwrap().save();
-        return bincode::serialize(&state).unwrap();
+    pub fn init(&mut self, romdata: Vec<u8>) -> Result<(), JsValue> {
+        let load = || {
+            let rom = cartridge::Cartridge::from_data(romdata)?;
+            let model = if rom.rom_info().supports_cgb() {
+                gameboy::Model::CGB
+            } else if rom.rom_info().supports_sgb() {
+                gameboy::Model::SGB
+            } else {
+                gameboy::Model::DMG
+            };
+            gameboy::GameboyBuilder::new().load_rom(rom).model(model).build()
+        };
+        let gameboy = load().map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.gameboy = Some(gameboy);
+        Ok(())
     }
-}
 
-#[wasm_bindgen]
-pub fn load_state(state: Vec<u8>) {
-    unsafe {
+    pub fn rom_info(&self) -> Result<cartridge::RomInfo, JsValue> {
+        Ok(self.gameboy()?.rom_info().clone())
+    }
+
+    pub fn update(&mut self, x: usize) -> Result<Vec<u32>, JsValue> {
+        let gameboy = self.gameboy_mut()?;
+        for _ in 0..x {
+            gameboy.tick();
+        }
+        Ok(gameboy.get_canvas().to_vec())
+    }
+
+    pub fn get_sgb_canvas(&self) -> Result<Vec<u32>, JsValue> {
+        self.gameboy()?
+            .get_sgb_canvas()
+            .ok_or_else(|| JsValue::from_str("Emulator is not running as a Super Game Boy"))
+    }
+
+    pub fn get_audio_buffer(&self) -> Result<Vec<f32>, JsValue> {
+        Ok(self.gameboy()?.get_audio_buffer().to_vec())
+    }
+
+    pub fn is_rumbling(&self) -> Result<bool, JsValue> {
+        Ok(self.gameboy()?.is_rumbling())
+    }
+
+    pub fn is_double_speed(&self) -> Result<bool, JsValue> {
+        Ok(self.gameboy()?.is_double_speed())
+    }
+
+    pub fn set_memory_blocking(&mut self, blocking: bool) -> Result<(), JsValue> {
+        self.gameboy_mut()?.set_memory_blocking(blocking);
+        Ok(())
+    }
+
+    // Plugs a link cable between this and another emulator on the same page
+    pub fn link_with(&mut self, other: &mut Emulator) -> Result<(), JsValue> {
+        let other = other.gameboy_mut()?;
+        self.gameboy_mut()?.link_with(other);
+        Ok(())
+    }
+
+    // Plugs a gameboy printer into the link cable, the strips it prints wait in take_printed_image
+    pub fn attach_printer(&mut self) -> Result<(), JsValue> {
+        let printed = self.printed.clone();
+        let on_print = move |img| printed.borrow_mut().push_back(img);
+        self.gameboy_mut()?
+            .set_serial_link(Box::new(printer::Printer::new(Box::new(on_print))));
+        Ok(())
+    }
+
+    // The oldest strip that hasn't been taken yet, as rgba pixels 160 wide
+    pub fn take_printed_image(&mut self) -> Option<Vec<u8>> {
+        self.printed.borrow_mut().pop_front().map(|img| img.to_rgba())
+    }
+
+    pub fn button_down(&mut self, b: isize) -> Result<(), JsValue> {
+        let bt = 1 << b; // ensure Emulator.ts and gameboy.rs have buttons in the same order
+        self.gameboy_mut()?.button_down(bt);
+        Ok(())
+    }
+
+    pub fn button_up(&mut self, b: isize) -> Result<(), JsValue> {
+        let bt = 1 << b; // ensure Emulator.ts and gameboy.rs have buttons in the same order
+        self.gameboy_mut()?.button_up(bt);
+        Ok(())
+    }
+
+    // Sets every button at once before the next frame, from a bitmap in the same order as button_down
+    pub fn set_input(&mut self, state: u8) -> Result<(), JsValue> {
+        self.gameboy_mut()?.set_input(state);
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Result<Vec<u8>, JsValue> {
+        let state = self.gameboy()?.save();
+        bincode::serialize(&state).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn load_state(&mut self, state: Vec<u8>) -> Result<(), JsValue> {
+        let gameboy = self.gameboy_mut()?;
         match bincode::deserialize(&state) {
             Ok(deser) => {
                 let save: cpu::SaveState = deser;
-                GAMEBOY.as_mut().unwrap().load(&save);
+                gameboy.load(&save);
+                Ok(())
             }
-            _ => panic!("Failed to load savestate"),
+            _ => Err(JsValue::from_str("Failed to load savestate")),
         }
     }
+
+    pub fn export_save_ram(&self) -> Result<Vec<u8>, JsValue> {
+        Ok(self.gameboy()?.export_save_ram())
+    }
+
+    pub fn import_save_ram(&mut self, data: Vec<u8>) -> Result<(), JsValue> {
+        self.gameboy_mut()?.import_save_ram(&data);
+        Ok(())
+    }
 }
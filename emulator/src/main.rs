@@ -1,6 +1,7 @@
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -13,7 +14,12 @@ mod cpu_recievable;
 mod debugger;
 mod gameboy;
 mod instruction;
+mod png;
 mod ppu;
+mod printer;
+mod serial;
+mod socket_link;
+mod sgb;
 mod timer;
 mod utils;
 
@@ -38,6 +44,14 @@ struct Args {
     stepmode: bool,
     breaks: Vec<u16>,
     watches: Vec<u16>,
+    model: gameboy::Model,
+    memory_blocking: bool,
+    printer: bool,
+    link: Option<Link>,
+}
+enum Link {
+    Listen(String),
+    Connect(String),
 }
 fn cleanup_screen(d: Display) {
     match d {
@@ -77,6 +91,13 @@ fn get_args() -> Args {
     let mut stepmode = false;
     let mut breaks = Vec::new();
     let mut watches = Vec::new();
+    // There is only one link port, so only one thing can be plugged into it
+    let links = ["--printer", "--link-listen", "--link-connect"];
+    let requested: Vec<&str> = links.iter().cloned().filter(|l| args.iter().any(|x| x == l)).collect();
+    if requested.len() > 1 {
+        println!("Only one of {} can be used at a time", requested.join(", "));
+        std::process::exit(0x01);
+    }
     if args.iter().any(|x| x == "--ascii") {
         display = Display::AsciiHalf;
         println!("Display: Ascii");
@@ -93,6 +114,21 @@ fn get_args() -> Args {
     if args.iter().any(|x| x == "--cpu") {
         display = Display::CPU;
     }
+    let model = if args.iter().any(|x| x == "--cgb") {
+        gameboy::Model::CGB
+    } else if args.iter().any(|x| x == "--sgb") {
+        gameboy::Model::SGB
+    } else {
+        gameboy::Model::DMG
+    };
+    let memory_blocking = !args.iter().any(|x| x == "--no-blocking");
+    let printer = args.iter().any(|x| x == "--printer");
+    let value_of = |flag: &str| args.iter().position(|x| x == flag).and_then(|i| args.get(i + 1)).cloned();
+    let link = if let Some(addr) = value_of("--link-listen") {
+        Some(Link::Listen(addr))
+    } else {
+        value_of("--link-connect").map(Link::Connect)
+    };
     for x in args.iter() {
         number_prefixed("-b", x).map(|n| breaks.push(n));
         number_prefixed("--break", x).map(|n| breaks.push(n));
@@ -104,10 +140,14 @@ fn get_args() -> Args {
         stepmode,
         watches,
         breaks,
+        model,
+        memory_blocking,
+        printer,
+        link,
     }
 }
 
-fn ascii_half_print(screen: &ppu::Screen) {
+fn ascii_half_print(screen: &ppu::Screen, rumbling: bool) {
     fn format_color(mut num: u8) -> u8 {
         match num & 0b11 {
             0 => 97,
@@ -132,12 +172,26 @@ fn ascii_half_print(screen: &ppu::Screen) {
         print!("{}[0m\n", ESC);
     }
     println!("{}[0m", ESC);
-    println!("Frame")
+    println!("Frame {}", if rumbling { "[Rumble]" } else { "        " })
+}
+
+fn save_file_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("sav")
+}
+
+fn flush_save_ram(gameboy: &gameboy::Gameboy, path: &Path) {
+    if gameboy.has_battery() {
+        if let Err(e) = std::fs::write(path, gameboy.export_save_ram()) {
+            println!("Failed to write save file {}: {}", path.display(), e);
+        }
+    }
 }
 
-fn main_loop(mut gameboy: gameboy::Gameboy, args: Args, saver: Saver) {
+fn main_loop(mut gameboy: gameboy::Gameboy, args: Args, saver: Saver, save_path: PathBuf) {
     let mut start = Instant::now();
     let mut frametime = Instant::now();
+    let mut flushtime = Instant::now();
+    let mut last_save = gameboy.export_save_ram();
 
     loop {
         match args.display {
@@ -152,14 +206,14 @@ fn main_loop(mut gameboy: gameboy::Gameboy, args: Args, saver: Saver) {
                 let duration = frametime.elapsed();
                 if duration.as_secs_f64() > (1.0 / 17.0) {
                     frametime = Instant::now();
-                    ascii_half_print(&gameboy.get_screen())
+                    ascii_half_print(&gameboy.get_screen(), gameboy.is_rumbling())
                 }
             }
         }
         gameboy.tick();
 
         let mut duration = start.elapsed();
-        let desiredtime = Duration::from_nanos(1000);
+        let desiredtime = Duration::from_nanos(if gameboy.is_double_speed() { 500 } else { 1000 });
         let elapsed = desiredtime.checked_sub(duration);
         match elapsed {
             None => start = start.checked_add(desiredtime).unwrap(),
@@ -169,6 +223,16 @@ fn main_loop(mut gameboy: gameboy::Gameboy, args: Args, saver: Saver) {
             }
         }
 
+        // Only touch the disk when the game actually wrote to its save ram
+        if flushtime.elapsed().as_secs() >= 5 {
+            flushtime = Instant::now();
+            let save = gameboy.export_save_ram();
+            if save != last_save {
+                flush_save_ram(&gameboy, &save_path);
+                last_save = save;
+            }
+        }
+
         let savestatefile = "savestate";
         match saver.lock().unwrap().pop_front() {
             Some(SignalOp::Break) => {
@@ -190,6 +254,13 @@ fn main_loop(mut gameboy: gameboy::Gameboy, args: Args, saver: Saver) {
                     _ => println!("Failed to load savestate"),
                 }
             }
+            Some(SignalOp::Quit) => {
+                println!("Cleaning up");
+                flush_save_ram(&gameboy, &save_path);
+                cleanup_screen(args.display);
+                println!("Bye!");
+                std::process::exit(0x01);
+            }
             None => (),
         }
     }
@@ -204,6 +275,7 @@ enum SignalOp {
     SaveState,
     LoadState,
     Break,
+    Quit,
 }
 type Saver = Arc<Mutex<VecDeque<SignalOp>>>;
 
@@ -213,15 +285,78 @@ fn main() {
     // let romdata = open_file("tetris.gb");
 //     let romdata = open_file("testrom/dtest2.gb");
     // let romdata = open_file("cpu_instrs_ld.gb");
-    let args: Vec<String> = env::args().collect();
-    let romdata = open_file(&args[1]);
-    let bios = open_file("bootrom.bin"); // gameboy state now starts after bootrom has complete
-    let mut gameboy = gameboy::GameboyBuilder::new()
-        .load_rom(cartridge::Cartridge::from_data(romdata))
-        .load_bios(bios)
-        .build();
-
+    let rom_path = env::args().nth(1).expect("no rom given");
     let args = get_args();
+    let romdata = open_file(&rom_path);
+    let save_path = save_file_path(&rom_path);
+    let rom = match cartridge::Cartridge::from_data(romdata) {
+        Ok(rom) => rom,
+        Err(e) => {
+            cleanup_screen(args.display);
+            println!("Failed to load {}: {}", &rom_path, e);
+            std::process::exit(0x01);
+        }
+    };
+    let mut builder = gameboy::GameboyBuilder::new()
+        .load_rom(rom)
+        .model(args.model)
+        .memory_blocking(args.memory_blocking);
+    match args.model {
+        gameboy::Model::DMG => builder = builder.load_bios(open_file("bootrom.bin")),
+        // Without a CGB boot rom we start in the post boot state
+        gameboy::Model::CGB if Path::new("cgb_bootrom.bin").exists() => {
+            builder = builder.load_bios(open_file("cgb_bootrom.bin"))
+        }
+        gameboy::Model::CGB => (),
+        gameboy::Model::SGB => (),
+    }
+    let mut gameboy = builder.build().expect("rom was given to the builder");
+    eprintln!("{}", gameboy.rom_info());
+
+    if gameboy.has_battery() && save_path.exists() {
+        gameboy.import_save_ram(&open_file(save_path.to_str().unwrap()));
+    }
+    // Keep whatever the game sends over the link cable, blargg's test roms print their results there
+    if !args.printer && args.link.is_none() {
+        if let Ok(f) = File::create("serial") {
+            gameboy.set_serial_link(Box::new(serial::Capture::new(f)));
+        }
+    }
+    // A gameboy printer on the link cable, each printed strip is saved as print-N.png
+    if args.printer {
+        let mut count = 0;
+        let on_print = move |img: printer::PrintedImage| {
+            count += 1;
+            let filename = format!("print-{}.png", count);
+            let height = img.shades.len() / printer::PRINT_WIDTH;
+            let saved = File::create(&filename)
+                .and_then(|mut f| png::write_rgba(&mut f, printer::PRINT_WIDTH, height, &img.to_rgba()));
+            match saved {
+                Ok(()) => eprintln!("Printed {}", filename),
+                Err(e) => eprintln!("Failed to save {}: {}", filename, e),
+            }
+        };
+        gameboy.set_serial_link(Box::new(printer::Printer::new(Box::new(on_print))));
+    }
+    // Another cli-runner on the other end of a link cable. addr is host:port, or a path for a unix socket
+    let link = match &args.link {
+        Some(Link::Listen(addr)) => {
+            eprintln!("Waiting for a link cable connection on {}", addr);
+            Some(socket_link::SocketLink::listen(addr))
+        }
+        Some(Link::Connect(addr)) => Some(socket_link::SocketLink::connect(addr)),
+        None => None,
+    };
+    match link {
+        Some(Ok(link)) => gameboy.set_serial_link(Box::new(link)),
+        Some(Err(e)) => {
+            cleanup_screen(args.display);
+            println!("Failed to set up the link cable: {}", e);
+            std::process::exit(0x01);
+        }
+        None => (),
+    }
+
     let d = args.display;
     let mut db = debugger::DebugOptions::default();
     let saver: Saver = Arc::new(Mutex::new(VecDeque::new()));
@@ -240,11 +375,16 @@ fn main() {
     db.watch_points = args.watches.clone();
     gameboy.set_debug_options(db);
 
+    // The save ram lives on the emulator thread, so ask it to flush and quit.
+    // A second ctrl-c exits straight away in case that thread is stuck
+    let saver2 = saver.clone();
     ctrlc::set_handler(move || {
-        println!("Cleaning up");
-        cleanup_screen(d);
-        println!("Bye!");
-        std::process::exit(0x01);
+        let mut ops = saver2.lock().unwrap();
+        if ops.iter().any(|x| matches!(x, SignalOp::Quit)) {
+            cleanup_screen(d);
+            std::process::exit(0x01);
+        }
+        ops.push_back(SignalOp::Quit);
     })
     .expect("Error setting Ctrl-C handler");
 
@@ -262,5 +402,5 @@ fn main() {
     //     }
     // });
 
-    main_loop(gameboy, args, saver);
+    main_loop(gameboy, args, saver, save_path);
 }
@@ -0,0 +1,100 @@
+use std::io::{self, Write};
+
+// Just enough of a png encoder for the printer's output. The image data goes
+// into zlib without any compression, the strips are small enough that it doesn't matter
+// http://www.libpng.org/pub/png/spec/1.2/PNG-Structure.html
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for d in data {
+        a = (a + *d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    w.write_all(&body)?;
+    w.write_all(&crc32(&body).to_be_bytes())
+}
+
+// Wraps the data in a zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Writes 8 bit rgba pixels as a png
+pub fn write_rgba<W: Write>(w: &mut W, width: usize, height: usize, rgba: &[u8]) -> io::Result<()> {
+    w.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]); // bit depth, rgba, compression, filter, interlace
+    write_chunk(w, b"IHDR", &header)?;
+
+    // Every scanline starts with its filter type, 0 is none
+    let mut raw = Vec::with_capacity((width * 4 + 1) * height);
+    for line in rgba.chunks(width * 4).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+    write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(w, b"IEND", &[])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_write_rgba() {
+        let mut out = Vec::new();
+        write_rgba(&mut out, 2, 1, &[0xFF, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF]).unwrap();
+        assert_eq!(out[..8].to_vec(), SIGNATURE.to_vec());
+        assert_eq!(out[12..16].to_vec(), b"IHDR".to_vec());
+        assert_eq!(out[16..24].to_vec(), vec![0, 0, 0, 2, 0, 0, 0, 1]);
+        // IEND is always the same 12 bytes
+        assert_eq!(out[out.len() - 12..].to_vec(), vec![0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+
+        let data = zlib_stored(&[0, 1, 2]);
+        assert_eq!(data, vec![0x78, 0x01, 0x01, 0x03, 0x00, 0xFC, 0xFF, 0, 1, 2, 0x00, 0x07, 0x00, 0x04]);
+    }
+}
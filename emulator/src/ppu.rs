@@ -1,808 +1,1897 @@
-use std::collections::VecDeque;
-use std::num::Wrapping;
-use serde::{Serialize, Deserialize};
-use crate::cpu_recievable::{Recievables, CpuRecievable::*, Interrupt};
-
-// TODO: add LCD_STAT interrupts
-pub const SCREEN_WIDTH: usize = 160;
-pub const SCREEN_HEIGHT: usize = 144;
-
-pub type Screen = Vec<u8>; // u8 array. This holds colors 0-3
-pub type Canvas = [u32; SCREEN_WIDTH * SCREEN_HEIGHT]; // rgba u32 array. This will get passed and loaded into canvas
-
-// https://gbdev.io/pandocs/#ff40-lcd-control-register
-// this register is a bitfield containing bits 76543210
-// Bit   Name                               Usage notes
-// 7     LCD Display Enable                 0=Off, 1=On
-// 6     Window Tile Map Display Select     0=9800-9BFF, 1=9C00-9FFF
-// 5     Window Display Enable              0=Off, 1=On
-// 4     BG & Window Tile Data Select       0=8800-97FF, 1=8000-8FFF
-// 3     BG Tile Map Display Select         0=9800-9BFF, 1=9C00-9FFF
-// 2     OBJ (Sprite) Size                  0=8x8, 1=8x16
-// 1     OBJ (Sprite) Display Enable        0=Off, 1=On
-// 0     BG and Window Display/Priority     0=Off, 1=On
-const LCD_CONTROL_REGISTER: usize = 0;
-
-// https://gbdev.io/pandocs/#lcd-status-register
-// Bit 6 - LYC=LY Coincidence Interrupt (1=Enable) (Read/Write)
-// Bit 5 - Mode 2 OAM Interrupt         (1=Enable) (Read/Write)
-// Bit 4 - Mode 1 V-Blank Interrupt     (1=Enable) (Read/Write)
-// Bit 3 - Mode 0 H-Blank Interrupt     (1=Enable) (Read/Write)
-// Bit 2 - Coincidence Flag  (0:LYC<>LY, 1:LYC=LY) (Read Only)
-// Bit 1-0 - Mode Flag       (Mode 0-3, see below) (Read Only)
-//           0: During H-Blank
-//           1: During V-Blank
-//           2: During Searching OAM
-//           3: During Transferring Data to LCD Driver
-const LCD_STATUS_REGISTER: usize = 1;
-
-// scroll x and y
-const SCY: usize = 2; // https://gbdev.io/pandocs/#ff42-scy-scroll-y-r-w-ff43-scx-scroll-x-r-w
-const SCX: usize = 3; // https://gbdev.io/pandocs/#ff42-scy-scroll-y-r-w-ff43-scx-scroll-x-r-w
-
-// current line, read only
-const LY: usize = 4; // https://gbdev.io/pandocs/#ff44-ly-lcdc-y-coordinate-r
-// This is read and writeable, bit 2 of lcd status is set if this equals ly
-const LYC: usize = 5; // https://gbdev.io/pandocs/#ff45-lyc-ly-compare-r-w
-
-const DMA: usize = 6; // https://gbdev.io/pandocs/#ff46-dma-dma-transfer-and-start-address-r-w
-
-// pallet, maps to the hex code below
-// bits 6-7: color for 11
-// bits 4-5: color for 10
-// bits 2-3: color for 01
-// bits 0-1: color for 00
-const BGP: usize = 7; // https://gbdev.io/pandocs/#ff47-bgp-bg-palette-data-r-w-non-cgb-mode-only
-// same as above, but bits 0-1 are ignore, as 00 is transparant for sprites
-const OBP0: usize = 8; // https://gbdev.io/pandocs/#ff48-obp0-object-palette-0-data-r-w-non-cgb-mode-only
-const OBP1: usize = 9; // https://gbdev.io/pandocs/#ff48-obp0-object-palette-0-data-r-w-non-cgb-mode-only
-
-// https://gbdev.io/pandocs/#ff4a-wy-window-y-position-r-w-ff4b-wx-window-x-position-7-r-w
-// Window x and y offsets
-const WY: usize = 0xA;
-const WX: usize = 0xB;
-
-#[derive(Clone,Copy,Debug, PartialEq)]
-pub enum Mode {
-    HBlank,
-    VBlank,
-    OAM,
-    VRAM,
-}
-
-const DMA_TRANSFER_SIZE: u8 = 160;
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct DMAManager {
-    start_location: u8,
-    progress: Option<u8>,
-}
-
-// TODO: This will be tricky, we have to block off CPU
-// access to memory while the transfer happens, as well as do the
-// transfer with proper timing.
-// Writing here replaces the whole OAM block with new data
-// at a rate of 1 byte per cycle.
-// For example, if you were to go LD $FF46, $10, the DMA would spend the next
-// 160 cycles copying memory from 1000-109F to FE00-FE9F
-// For example, if you were to go LD $FF46, $49, the DMA would spend the next
-// 160 cycles copying the 160 bytes from 4900-499F to FE00-FE9F
-impl DMAManager {
-    fn new() -> Self {
-        Self {
-            start_location: 0,
-            progress: None,
-        }
-    }
-
-    pub fn next(&mut self) -> Option<(u16, u16)> {
-        self.progress = self.progress.map(|x| x + 1).and_then(|x| if x > DMA_TRANSFER_SIZE { None } else { Some(x) });
-        self.progress.map(|x| {
-            let x = x - 1;
-            // println!("LOC: {:02X} {:02X}", self.start_location, x);
-            let from: u16 = ((self.start_location as u16) << 8) + x as u16;
-            let to: u16 = 0xFE00 + x as u16;
-            (from, to)
-        })
-    }
-
-    fn start_transfer(&mut self, val: u8) {
-        self.start_location = val;
-        self.progress = Some(0);
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct PPU {
-    screen: Screen,
-    vram: Vec<u8>, // size of 0x2000
-    registers: Vec<u8>,// size of 16
-    tick: usize,
-    oam_ram: Vec<Sprite>, // size of 40
-    spriteline: Vec<PixelData>,
-    pixel_fifo: VecDeque<PixelData>,
-    pixels_pushed: usize,
-    fetch_state: Wrapping<u8>,
-    lx: u8,
-    is_window: bool,
-    #[serde(skip, default="crate::cpu_recievable::none_recivables")]
-    recievables: Option<Recievables>,
-    pub dma: DMAManager,
-}
-
-impl Clone for PPU {
-    fn clone(&self) -> Self {
-        Self {
-            screen: self.screen.clone(),
-            vram: self.vram.clone(), // size of 0x2000
-            registers: self.registers.clone(),// size of 16
-            tick: self.tick,
-            oam_ram: self.oam_ram.clone(),
-            spriteline: self.spriteline.clone(),
-            pixel_fifo: self.pixel_fifo.clone(),
-            pixels_pushed: self.pixels_pushed.clone(),
-            fetch_state: self.fetch_state.clone(),
-            lx: self.lx,
-            is_window: self.is_window,
-            recievables: None,
-            dma: self.dma.clone(),
-        }
-    }
-}
-
-const TICK_WIDTH: usize = 456;
-const OAM_WIDTH: usize = 80;
-const EFFECTIVE_SCAN_COUNT: u8 = 153;
-
-const color00: u8 = 0b00;
-const color01: u8 = 0b01;
-const color10: u8 = 0b10;
-const color11: u8 = 0b11;
-
-#[derive(Copy, Clone, Debug)]
-#[derive(Serialize, Deserialize)]
-struct Sprite {
-    pos_x: u8,
-    pos_y: u8,
-    tile: u8,
-    flags: u8,
-}
-
-impl Sprite {
-    const fn new() -> Self {
-        Sprite {
-            pos_x: 0,
-            pos_y: 0,
-            tile:  0,
-            flags: 0,
-        }
-    }
-    fn from_memory(mem: &[u8; 0x2000], loc: usize) -> Self {
-        Sprite {
-            pos_y: mem[loc+0],
-            pos_x: mem[loc+1],
-            tile:  mem[loc+2],
-            flags: mem[loc+3],
-        }
-    }
-
-    fn priority(&self) -> u8 {
-        (self.flags & 0b1000) >> 3
-    }
-    fn is_x_flipped(&self) -> bool {
-        self.flags & 0b0100 > 0
-    }
-    fn is_y_flipped(&self) -> bool {
-        self.flags & 0b0100 > 0
-    }
-    fn palette(&self) -> PixelSrc {
-        if self.flags & 0b0100 > 0 {
-            PixelSrc::S2
-        } else {
-            PixelSrc::S1
-        }
-    }
-}
-
-#[derive(Clone,Copy,Debug, PartialEq)]
-#[derive(Serialize, Deserialize)]
-enum PixelSrc {
-    BG, S1, S2
-}
-#[derive(Clone,Copy,Debug, PartialEq)]
-#[derive(Serialize, Deserialize)]
-struct PixelData {
-    value: u8, // Really this is a 2 bit number
-    src: PixelSrc,
-}
-
-impl std::fmt::Display for PixelData {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let s = match self.src {
-            PixelSrc::BG => "BG",
-            PixelSrc::S1 => "S1",
-            PixelSrc::S2 => "S2",
-        };
-        write!(f, "<{}::{:02b}>", s, self.value)
-    }
-}
-
-
-impl PPU {
-    pub fn new() -> Self {
-        let mut ppu =
-            PPU {
-                screen: [color00; SCREEN_WIDTH * SCREEN_HEIGHT].to_vec(),
-                vram: [0u8; 0x2000].to_vec(),
-                registers: [0u8; 0x10].to_vec(),
-                oam_ram: [Sprite::new(); 40].to_vec(),
-                tick: 0,
-                spriteline: Vec::new(),
-                pixel_fifo: VecDeque::new(),
-                pixels_pushed: 0,
-                fetch_state: Wrapping(0),
-                is_window: false,
-                lx: 0,
-                recievables: None,
-                dma: DMAManager::new(),
-            };
-        ppu.registers[LY] = 143;
-        ppu
-    }
-
-    pub fn get_canvas(&self) -> Canvas {
-        // Hex colors need to be in ABGR order for direct loading
-        const color00: u32 = 0xFF0FBC9C;
-        const color01: u32 = 0xFF0FAC8B;
-        const color10: u32 = 0xFF306230;
-        const color11: u32 = 0xFF0F380F;
-        let mut canvas = [0u32; SCREEN_WIDTH * SCREEN_HEIGHT];
-        for i in 0..(SCREEN_WIDTH * SCREEN_HEIGHT) {
-            canvas[i] = match self.screen[i] {
-                0b00 => color00,
-                0b01 => color01,
-                0b10 => color10,
-                _ => color11,
-            }
-        }
-        canvas
-
-    }
-
-    pub fn get_screen(&self) -> &Screen {
-        &self.screen
-    }
-
-    fn lookup_color(&self, p: PixelData, old: u8) -> u8 {
-        let palette = match p.src {
-            PixelSrc::BG => self.registers[BGP],
-            PixelSrc::S1 => self.registers[OBP0],
-            PixelSrc::S2 => self.registers[OBP1],
-        };
-        let num = palette >> ((p.value & 0b11) << 1) & 0b11;
-        if match p.src {PixelSrc::BG => false, _ => true} {
-            if p.value == 0 {
-                return old
-            }
-
-        }
-        match num {
-            0b00 => color00,
-            0b01 => color01,
-            0b10 => color10,
-            0b11 => color11,
-            _ => unreachable!("number should be mod 4"),
-        }
-    }
-
-    fn tilemap_loc(&self, num: u8) -> u16 {
-        if num > 0 {
-            return 0x1C00 // 9C00 - 8000
-        } else {
-            return 0x1800 // 9800 - 8000
-        }
-
-    }
-    fn get_effective_y(&self) -> u8 {
-        let y = ((self.registers[LY] as u16 + self.registers[SCY] as u16) & 0xFF) as u8;
-        y
-    }
-    fn window_or_background_tilemap_loc(&self, tilemap_loc: u16) -> usize {
-        let y = self.get_effective_y();
-        let x = self.lx.wrapping_add(self.pixel_fifo.len() as u8);
-        let offset = (y as usize) / 8 * 32 + (x as usize) / 8;
-        let tile_idx = self.vram[tilemap_loc as usize + offset];
-        if self.registers[LCD_CONTROL_REGISTER] & 0b10000 > 0 {
-            return tile_idx as usize * 16;
-        } else {
-            return ((tile_idx as i8) as isize * 16 + 0x1000) as usize;
-        }
-
-    }
-    fn background_tilemap_loc(&self) -> usize {
-        let tilemap_loc = self.tilemap_loc(self.registers[LCD_CONTROL_REGISTER] & 0b00001000);
-        self.window_or_background_tilemap_loc(tilemap_loc)
-    }
-    fn window_tilemap_loc(&self) -> usize {
-        let tilemap_loc = self.tilemap_loc(self.registers[LCD_CONTROL_REGISTER] & 0b01000000);
-        self.window_or_background_tilemap_loc(tilemap_loc)
-    }
-    fn sprite_tile_loc(&self, idx: u8) -> usize {
-        idx as usize * 16
-    }
-
-    fn decode_tile(&self, loc: usize, line: usize) -> [PixelData; 8] {
-        let vloc = loc + line * 2;
-        let bg_tile_low = self.vram[vloc];
-        let bg_tile_high = self.vram[vloc + 1];
-        let gen = move |i: usize| {
-            let bh = (bg_tile_high >> (7 - i)) & 1;
-            let bl = (bg_tile_low >> (7 - i)) & 1;
-            bh << 1 | bl
-        };
-        let mut v = [PixelData{value: 0, src: PixelSrc::BG}; 8];
-        for i in 0..v.len() {
-            v[i].value = gen(i)
-        }
-        // println!("Tile loc: {:04X} on line {}. Effective y is {}. Decoded to {:?}",
-        //  loc + 0x8000, line,
-        //  (self.registers[LY] as usize + self.registers[SCY] as usize) & 0xFF,
-        //  v
-        // );
-        v
-
-    }
-
-    fn fetch(&mut self) -> Option<[PixelData; 8]> {
-        self.fetch_state += Wrapping(1);
-        if let 0 = (self.fetch_state & Wrapping(0b111)).0 { // only update on last part of cycle
-            // TODO: Window/Obj lookup
-            let bg = self.background_tilemap_loc();
-            //println!("{}", bg);
-            let y = self.get_effective_y();
-            let loc = bg as usize;
-            Some(self.decode_tile(loc, (y & 0b111) as usize))
-        } else {
-            None
-        }
-    }
-
-    fn sendif(&mut self, i: Interrupt) {
-        match &self.recievables {
-            Some(r) => r.send(SendInterrupt(i)),
-            None => ()
-        }
-    }
-    pub fn tick(&mut self) {
-        self.tick += 1;
-        self.registers[LCD_STATUS_REGISTER] &= 0b11111011 |
-            if self.registers[LY] == self.registers[LYC] { self.sendif(Interrupt::LCDStat); 1 }
-            else { 0 } << 2;
-        match self.get_mode() {
-            Mode::HBlank => {
-                if self.tick > TICK_WIDTH {
-                    self.registers[LY] += 1;
-                    if self.registers[LY] >= 144 {
-                        self.sendif(Interrupt::VBlank);
-                        self.set_mode(Mode::VBlank);
-                    } else {
-                        self.set_mode(Mode::OAM);
-                    }
-                    self.tick = 0;
-                }
-            },
-            Mode::VBlank => {
-                if self.tick > TICK_WIDTH {
-                    self.registers[LY] += 1;
-                    if self.registers[LY] > EFFECTIVE_SCAN_COUNT {
-                        self.registers[LY] = 0;
-                        self.set_mode(Mode::OAM);
-                    }
-                    self.tick = 0;
-                }
-            },
-            Mode::OAM => {
-                if self.tick == 1 {
-                } else if self.tick == OAM_WIDTH {
-                    // OAM lookup, this is normally done over 20 dots, but we'll just do it at the end
-                    let mut i = 0;
-                    let s_size = 8;
-                    let ly = self.registers[LY];
-
-                    let mut data = [PixelData {
-                        src: PixelSrc::S1,
-                        value: 0b00,
-                    }; 168];
-                    for j in 0..40 {
-                        let sj = self.oam_ram[j];
-                        if sj.pos_x != 0 && ly + 16 >= sj.pos_y && ly + 16 < sj.pos_y + s_size {
-                            let ey = 16 + ly as isize - sj.pos_y as isize;
-                            let sp = self.decode_tile(self.sprite_tile_loc(sj.tile), ey as usize);
-                            for i in 0..8 {
-                                let tx = sj.pos_x as usize + i;
-                                if tx < 168 {
-                                    data[tx] = sp[i]
-                                }
-                            }
-                            i += 1;
-                        }
-                        if i >= 10 {
-                            break;
-                        }
-                    }
-                    self.spriteline = data[8..].to_vec();
-                    self.set_mode(Mode::VRAM);
-                }
-            },
-            Mode::VRAM => {
-                // render a pixel
-                // 8 pixel cycle with fetcher
-                // FIFO runs at 4 MHz, Fetch runs at 2MHz
-                // FIFO     FETCH
-                // Push     Read Tile #
-                // Push
-                // Push     Read Data 0
-                // Push
-                // Push     Read Data 1
-                // Push
-                // Push     Idle
-                // Push
-                //
-                // When we hit window, the fifo is cleared, and the fetch switches to window
-                let in_window = self.pixels_pushed >= self.registers[WX] as usize && self.registers[LY] >= self.registers[WY];
-                let valid_window = in_window && self.registers[LCD_CONTROL_REGISTER] & 0b10000 > 0;
-                if valid_window && !self.is_window { // window enabled
-                    self.is_window = true;
-                    self.pixel_fifo.clear();
-                } else if self.is_window && !valid_window {
-                    self.is_window = false;
-                    self.pixel_fifo.clear();
-                }
-
-                if self.pixel_fifo.len() > 8 {
-                    let p = self.pixel_fifo.pop_front().unwrap(); // checked in the if statement
-                    let y = self.registers[LY];
-                    let x = self.pixels_pushed;
-                    let old_color = self.screen[(x as usize) + (y as usize) * SCREEN_WIDTH];
-                    let color_bg = self.lookup_color(p, old_color);
-                    let color = self.lookup_color(*self.spriteline.get(x).unwrap(), color_bg);
-                    //println!("Pixel {}: (x, y)[{},{}] -> Color: {:X}", p, x, y, color);
-                    if self.lx >= self.registers[SCX] {
-                        self.screen[(x as usize) + (y as usize) * SCREEN_WIDTH] = color;
-                        self.pixels_pushed += 1;
-                    }
-                    self.lx = self.lx.wrapping_add(1);
-                }
-
-                let new_pixels = self.fetch();
-                if let Some(px) = new_pixels {
-                    for p in px.iter() {
-                        self.pixel_fifo.push_back(*p);
-                    }
-                }
-
-                if self.pixels_pushed >= 160 {
-                    self.pixels_pushed = 0;
-                    self.lx = 0;
-                    self.set_mode(Mode::HBlank);
-                    self.pixel_fifo.clear();
-                }
-            },
-        }
-    }
-    fn set_mode(&mut self, mode: Mode) {
-        let v = match mode {
-            Mode::HBlank => 0b00,
-            Mode::VBlank => 0b01,
-            Mode::OAM => 0b10,
-            Mode::VRAM => 0b11,
-        };
-
-        self.registers[LCD_STATUS_REGISTER] &= 0b11111100;
-        self.registers[LCD_STATUS_REGISTER] |= v;
-    }
-    fn get_mode(&self) -> Mode {
-        match self.registers[LCD_STATUS_REGISTER] & 0b11 {
-            0b00 => Mode::HBlank,
-            0b01 => Mode::VBlank,
-            0b10 => Mode::OAM,
-            0b11 => Mode::VRAM,
-            _ => unreachable!("exhaustive match pattern")
-        }
-    }
-
-    // Both read and write expect loc to be in the address range 0x8000..=0x9FFF
-    pub fn write(&mut self, loc: u16, val: u8) {
-        let l = loc as usize - 0x8000;
-        // print!("PPU Write: [{:04X}] = {:02X}. During mode {:?}\n", loc, val, self.get_mode());
-        match self.get_mode() {
-            Mode::VRAM => self.vram[l] = val, // TODO: do nothing
-            _ => self.vram[l] = val,
-        }
-    }
-    pub fn read(&self, loc: u16) -> u8 {
-        let l = loc as usize - 0x8000;
-        match self.get_mode() {
-            Mode::VRAM => self.vram[l], // TODO: this should be 0xFF
-            _ => self.vram[l],
-        }
-    }
-    pub fn writeOAM(&mut self, loc: u16, val: u8) {
-        let l = (loc as usize - 0xFE00) / 4;
-        match self.get_mode() {
-            Mode::VRAM => (),
-            Mode::OAM => (),
-            _ => match loc & 0b11 {
-                0 => self.oam_ram[l].pos_y = val,
-                1 => self.oam_ram[l].pos_x = val,
-                2 => self.oam_ram[l].tile = val,
-                3 => self.oam_ram[l].flags = val,
-                _ => unreachable!("exhaustive match pattern")
-            }
-        }
-    }
-    pub fn readOAM(&self, loc: u16) -> u8 {
-        let l = (loc as usize - 0xFE00) / 4;
-        match self.get_mode() {
-            Mode::VRAM => 0xFF,
-            Mode::OAM => 0xFF,
-            _ => match loc & 0b11 {
-                    0 => self.oam_ram[l].pos_y,
-                    1 => self.oam_ram[l].pos_x,
-                    2 => self.oam_ram[l].tile,
-                    3 => self.oam_ram[l].flags,
-                    _ => unreachable!("exhaustive match pattern")
-                }
-        }
-    }
-
-    pub fn write_reg(&mut self, loc: u16, val: u8) {
-        let l = loc as usize - 0xFF40;
-        self.registers[l] = val;
-        if l > 0xB {
-            panic!("CGB functionallity is not supported")
-        } else if l == DMA {
-            self.dma.start_transfer(val);
-        } else if l == LY {
-            panic!("0xFF44 is read only")
-        }
-    }
-    pub fn read_reg(&self, loc: u16) -> u8 {
-        let l = loc as usize - 0xFF40;
-        if l > 0xB {
-            //panic!("CGB functionallity is not supported")
-                return 0xff
-        }
-        self.registers[l]
-    }
-
-    pub fn set_recievables(&mut self, recievables: Recievables) {
-        self.recievables = Some(recievables)
-    }
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-
-    fn create_test_ppu() -> PPU {
-        PPU::new()
-    }
-
-    #[test]
-    fn test_pixel_color_lookup () {
-        let mut ppu = create_test_ppu();
-
-        ppu.registers[BGP] = 0b11100100;
-        ppu.registers[OBP0] = 0b11100100;
-        assert_eq!(color00, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b00,
-        }, 0b00));
-        assert_eq!(color01, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b01,
-        }, 0b00));
-        assert_eq!(color10, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b10,
-        }, 0b00));
-        assert_eq!(color11, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b11,
-        }, 0b00));
-
-        ppu.registers[BGP] = 0b10110001;
-        assert_eq!(color01, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b00,
-        }, 0b00));
-        assert_eq!(color00, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b01,
-        }, 0b00));
-        assert_eq!(color11, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b10,
-        }, 0b00));
-        assert_eq!(color10, ppu.lookup_color(PixelData{
-            src: PixelSrc::BG,
-            value: 0b11,
-        }, 0b00));
-
-    }
-
-    #[test]
-    fn test_tile_decode () {
-        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
-                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
-        let mut ppu = create_test_ppu();
-        ppu.registers[BGP] = 0b11100100;
-        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
-        ppu.lx = 0;
-        ppu.registers[LY] = 0;
-        for i in 0..testtile.len(){
-            ppu.vram[i+16] = testtile[i];
-        }
-
-        for i in 0..(32*32) {
-            ppu.vram[0x1800 + i] = 1;
-        }
-        for i in 0..7 {
-            assert_eq!(None, ppu.fetch())
-        }
-        let p00 = PixelData{src: PixelSrc::BG, value: 0b00};
-        let p01 = PixelData{src: PixelSrc::BG, value: 0b01};
-        let p10 = PixelData{src: PixelSrc::BG, value: 0b10};
-        let p11 = PixelData{src: PixelSrc::BG, value: 0b11};
-        assert_eq!(Some([p00, p00, p00, p00, p01, p01, p01, p01]), ppu.fetch());
-
-        ppu.lx = 0;
-        ppu.registers[LY] = 1;
-        for i in 0..7 {
-            assert_eq!(None, ppu.fetch())
-        }
-        assert_eq!(Some([p00, p00, p00, p00, p01, p01, p01, p01]), ppu.fetch());
-
-        ppu.lx = 0;
-        ppu.registers[LY] = 4;
-        for i in 0..7 {
-            assert_eq!(None, ppu.fetch())
-        }
-        assert_eq!(Some([p10, p10, p10, p10, p11, p11, p11, p11]), ppu.fetch());
-
-        ppu.lx = 0;
-        ppu.registers[LY] = 0;
-        ppu.registers[SCY] = 6;
-        for i in 0..7 {
-            assert_eq!(None, ppu.fetch())
-        }
-        assert_eq!(Some([p10, p10, p10, p10, p11, p11, p11, p11]), ppu.fetch());
-    }
-
-    #[test]
-    fn test_ppu_tick () {
-        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
-                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
-        let mut ppu = create_test_ppu();
-        ppu.registers[BGP] = 0b11100100;
-        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
-        assert_eq!(ppu.get_mode(), Mode::HBlank);
-        let offset = 9;
-        for i in 0..(TICK_WIDTH+1) {
-            ppu.tick();
-        }
-        ppu.registers[LY] = 0;
-        ppu.registers[SCY] = 16;
-        assert_eq!(ppu.get_mode(), Mode::VBlank);
-        for i in 0..testtile.len(){
-            ppu.vram[i+offset*16] = testtile[i];
-        }
-        for i in 0..(32*32) {
-            ppu.vram[0x1800 + i] = offset as u8;
-        }
-        for _ in 0..(80 + 250) {
-            ppu.tick();
-        }
-        assert_eq!(ppu.get_mode(), Mode::VBlank);
-        while ppu.get_mode() == Mode::VBlank {
-            ppu.tick();
-        }
-        while ppu.get_mode() != Mode::VBlank {
-            ppu.tick();
-        }
-
-        let line = 144 - 8;
-        for j in line..(line+8) {
-            for i in 0..8{
-                print!("{:X} ", ppu.screen[SCREEN_WIDTH*j + i]);
-            }
-            println!();
-        }
-
-        for j in 0..SCREEN_HEIGHT {
-            for i in 0..SCREEN_WIDTH {
-                let should_color =
-                    if (i / 4) % 2 == 0 {
-                        if (j / 4) % 2 == 0 {
-                            color00
-                        } else {
-                            color10
-                        }
-                    } else {
-                        if (j / 4) % 2 == 0 {
-                            color01
-                        } else {
-                            color11
-                        }
-                    };
-                //println!("Checking ({}, {}) to be {}", i, j, format!("{:X}", should_color));
-                assert_eq!(format!("{:X}", ppu.screen[SCREEN_WIDTH * j + i]), format!("{:X}", should_color));
-                // assert_eq!(ppu.screen[i], should_color);
-            }
-        }
-    }
-
-    #[test]
-    fn test_ppu_tick_scroll () {
-        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
-                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
-        let mut ppu = create_test_ppu();
-        ppu.registers[BGP] = 0b11100100;
-        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
-        ppu.registers[SCX] = 1;
-        assert_eq!(ppu.get_mode(), Mode::HBlank);
-        let offset = 9;
-        for i in 0..(TICK_WIDTH+1) {
-            ppu.tick();
-        }
-        ppu.registers[LY] = 0;
-        ppu.registers[SCY] = 16;
-        assert_eq!(ppu.get_mode(), Mode::VBlank);
-        for i in 0..testtile.len(){
-            ppu.vram[i+offset*16] = testtile[i];
-        }
-        for i in 0..(32*32) {
-            ppu.vram[0x1800 + i] = offset as u8;
-        }
-        for _ in 0..(80 + 250) {
-            ppu.tick();
-        }
-        assert_eq!(ppu.get_mode(), Mode::VBlank);
-        while ppu.get_mode() == Mode::VBlank {
-            ppu.tick();
-        }
-        while ppu.get_mode() != Mode::VBlank {
-            ppu.tick();
-        }
-
-        let line = 144 - 8;
-        for j in line..(line+8) {
-            for i in 0..8{
-                print!("{:X} ", ppu.screen[SCREEN_WIDTH*j + i]);
-            }
-            println!();
-        }
-
-        for j in 0..SCREEN_HEIGHT {
-            for i in 0..SCREEN_WIDTH {
-                let should_color =
-                    if ((i+1) / 4) % 2 == 0 {
-                        if (j / 4) % 2 == 0 {
-                            color00
-                        } else {
-                            color10
-                        }
-                    } else {
-                        if (j / 4) % 2 == 0 {
-                            color01
-                        } else {
-                            color11
-                        }
-                    };
-                //println!("Checking ({}, {}) to be {}", i, j, format!("{:X}", should_color));
-                assert_eq!(format!("{:X}", ppu.screen[SCREEN_WIDTH * j + i]), format!("{:X}", should_color));
-                // assert_eq!(ppu.screen[i], should_color);
-            }
-        }
-    }
-}
+use std::collections::VecDeque;
+use serde::{Serialize, Deserialize};
+use crate::cpu_recievable::{Recievables, CpuRecievable::*, Interrupt};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub type Screen = Vec<u8>; // u8 array. This holds colors 0-3
+pub type Canvas = [u32; SCREEN_WIDTH * SCREEN_HEIGHT]; // rgba u32 array. This will get passed and loaded into canvas
+
+// https://gbdev.io/pandocs/#ff40-lcd-control-register
+// this register is a bitfield containing bits 76543210
+// Bit   Name                               Usage notes
+// 7     LCD Display Enable                 0=Off, 1=On
+// 6     Window Tile Map Display Select     0=9800-9BFF, 1=9C00-9FFF
+// 5     Window Display Enable              0=Off, 1=On
+// 4     BG & Window Tile Data Select       0=8800-97FF, 1=8000-8FFF
+// 3     BG Tile Map Display Select         0=9800-9BFF, 1=9C00-9FFF
+// 2     OBJ (Sprite) Size                  0=8x8, 1=8x16
+// 1     OBJ (Sprite) Display Enable        0=Off, 1=On
+// 0     BG and Window Display/Priority     0=Off, 1=On
+const LCD_CONTROL_REGISTER: usize = 0;
+
+// https://gbdev.io/pandocs/#lcd-status-register
+// Bit 6 - LYC=LY Coincidence Interrupt (1=Enable) (Read/Write)
+// Bit 5 - Mode 2 OAM Interrupt         (1=Enable) (Read/Write)
+// Bit 4 - Mode 1 V-Blank Interrupt     (1=Enable) (Read/Write)
+// Bit 3 - Mode 0 H-Blank Interrupt     (1=Enable) (Read/Write)
+// Bit 2 - Coincidence Flag  (0:LYC<>LY, 1:LYC=LY) (Read Only)
+// Bit 1-0 - Mode Flag       (Mode 0-3, see below) (Read Only)
+//           0: During H-Blank
+//           1: During V-Blank
+//           2: During Searching OAM
+//           3: During Transferring Data to LCD Driver
+const LCD_STATUS_REGISTER: usize = 1;
+
+// scroll x and y
+const SCY: usize = 2; // https://gbdev.io/pandocs/#ff42-scy-scroll-y-r-w-ff43-scx-scroll-x-r-w
+const SCX: usize = 3; // https://gbdev.io/pandocs/#ff42-scy-scroll-y-r-w-ff43-scx-scroll-x-r-w
+
+// current line, read only
+const LY: usize = 4; // https://gbdev.io/pandocs/#ff44-ly-lcdc-y-coordinate-r
+// This is read and writeable, bit 2 of lcd status is set if this equals ly
+const LYC: usize = 5; // https://gbdev.io/pandocs/#ff45-lyc-ly-compare-r-w
+
+const DMA: usize = 6; // https://gbdev.io/pandocs/#ff46-dma-dma-transfer-and-start-address-r-w
+
+// pallet, maps to the hex code below
+// bits 6-7: color for 11
+// bits 4-5: color for 10
+// bits 2-3: color for 01
+// bits 0-1: color for 00
+const BGP: usize = 7; // https://gbdev.io/pandocs/#ff47-bgp-bg-palette-data-r-w-non-cgb-mode-only
+// same as above, but bits 0-1 are ignore, as 00 is transparant for sprites
+const OBP0: usize = 8; // https://gbdev.io/pandocs/#ff48-obp0-object-palette-0-data-r-w-non-cgb-mode-only
+const OBP1: usize = 9; // https://gbdev.io/pandocs/#ff48-obp0-object-palette-0-data-r-w-non-cgb-mode-only
+
+// https://gbdev.io/pandocs/#ff4a-wy-window-y-position-r-w-ff4b-wx-window-x-position-7-r-w
+// Window x and y offsets
+const WY: usize = 0xA;
+const WX: usize = 0xB;
+
+// https://gbdev.io/pandocs/#ff4f-vbk-cgb-mode-only-vram-bank
+const VBK: u16 = 0xFF4F;
+
+// https://gbdev.io/pandocs/#lcd-color-palettes-cgb-only
+// BCPS/OCPS select a byte of palette ram with bits 0-5, bit 7 increments the
+// index after every write to BCPD/OCPD. There are 8 palettes of 4 colors,
+// each color is a little endian 15 bit 0BBBBBGGGGGRRRRR value
+const BCPS: u16 = 0xFF68;
+const BCPD: u16 = 0xFF69;
+const OCPS: u16 = 0xFF6A;
+const OCPD: u16 = 0xFF6B;
+const PALETTE_RAM_SIZE: usize = 64;
+
+// https://gbdev.io/pandocs/#bg-map-attributes-cgb-mode-only
+// Stored in vram bank 1 at the same location as the tile number in bank 0
+// Bit 7    BG-to-OAM Priority         (0=Use OAM priority bit, 1=BG Priority)
+// Bit 6    Vertical Flip              (0=Normal, 1=Mirror vertically)
+// Bit 5    Horizontal Flip            (0=Normal, 1=Mirror horizontally)
+// Bit 4    Not used
+// Bit 3    Tile VRAM Bank number      (0=Bank 0, 1=Bank 1)
+// Bit 2-0  Background Palette number  (BGP0-7)
+const VRAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Clone,Copy,Debug, PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OAM,
+    VRAM,
+}
+
+const DMA_TRANSFER_SIZE: u8 = 160;
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DMAManager {
+    start_location: u8,
+    progress: Option<u8>,
+}
+
+// https://gbdev.io/pandocs/#oam-dma-transfer
+// While the transfer happens the cpu can only access HRAM, see Bus::is_dma_blocked
+// Writing here replaces the whole OAM block with new data
+// at a rate of 1 byte per cycle.
+// For example, if you were to go LD $FF46, $10, the DMA would spend the next
+// 160 cycles copying memory from 1000-109F to FE00-FE9F
+// For example, if you were to go LD $FF46, $49, the DMA would spend the next
+// 160 cycles copying the 160 bytes from 4900-499F to FE00-FE9F
+impl DMAManager {
+    fn new() -> Self {
+        Self {
+            start_location: 0,
+            progress: None,
+        }
+    }
+
+    pub fn next(&mut self) -> Option<(u16, u16)> {
+        self.progress = self.progress.map(|x| x + 1).and_then(|x| if x > DMA_TRANSFER_SIZE { None } else { Some(x) });
+        self.progress.map(|x| {
+            let x = x - 1;
+            // println!("LOC: {:02X} {:02X}", self.start_location, x);
+            let from: u16 = ((self.start_location as u16) << 8) + x as u16;
+            let to: u16 = 0xFE00 + x as u16;
+            (from, to)
+        })
+    }
+
+    fn start_transfer(&mut self, val: u8) {
+        self.start_location = val;
+        self.progress = Some(0);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.progress.is_some()
+    }
+}
+
+// https://gbdev.io/pandocs/#lcd-vram-dma-transfers-cgb-only
+// FF51 HDMA1  Source, High
+// FF52 HDMA2  Source, Low (lower 4 bits ignored)
+// FF53 HDMA3  Destination, High (only bits 0-4 are used, always in vram)
+// FF54 HDMA4  Destination, Low (lower 4 bits ignored)
+// FF55 HDMA5  Length/Mode/Start
+//   Bit 7      0=General Purpose DMA, 1=H-Blank DMA
+//   Bit 0-6    Length divided by 16, minus 1
+// A general purpose DMA copies everything at once, an H-Blank DMA copies
+// 16 bytes at the start of every H-Blank. The CPU is stalled while either copies.
+// Writing FF55 with bit 7 clear during an H-Blank DMA cancels it. Reading FF55
+// gives the blocks left minus 1, with bit 7 set when no H-Blank DMA is running
+pub const HDMA_BLOCK_SIZE: u16 = 16;
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HDMAManager {
+    source: u16,
+    dest: u16, // offset into vram
+    blocks: u8, // 16 byte blocks left
+    burst: u16, // bytes left to copy before the CPU can continue
+    hblank_mode: bool,
+    paused: bool, // H-Blank blocks are skipped while the CPU is halted
+}
+
+impl HDMAManager {
+    fn new() -> Self {
+        Self {
+            source: 0,
+            dest: 0,
+            blocks: 0,
+            burst: 0,
+            hblank_mode: false,
+            paused: false,
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) {
+        match loc {
+            0xFF51 => self.source = (self.source & 0x00FF) | (val as u16) << 8,
+            0xFF52 => self.source = (self.source & 0xFF00) | (val as u16 & 0xF0),
+            0xFF53 => self.dest = (self.dest & 0x00FF) | (val as u16 & 0x1F) << 8,
+            0xFF54 => self.dest = (self.dest & 0xFF00) | (val as u16 & 0xF0),
+            0xFF55 if self.hblank_mode && val & 0b10000000 == 0 => self.hblank_mode = false,
+            0xFF55 => {
+                self.blocks = (val & 0x7F) + 1;
+                self.hblank_mode = val & 0b10000000 > 0;
+                if !self.hblank_mode {
+                    self.burst = self.blocks as u16 * HDMA_BLOCK_SIZE;
+                }
+            }
+            _ => panic!("Address should not have been routed to hdma"),
+        }
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0xFF55 => {
+                let left = self.blocks.wrapping_sub(1) & 0x7F;
+                if self.hblank_mode { left } else { 0b10000000 | left }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn is_transferring(&self) -> bool {
+        self.burst > 0
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    fn hblank(&mut self) {
+        if self.hblank_mode && !self.paused && self.burst == 0 {
+            self.burst = HDMA_BLOCK_SIZE;
+        }
+    }
+
+    // Returns the next (from, to) pair to copy, like the OAM DMA
+    pub fn next(&mut self) -> Option<(u16, u16)> {
+        if self.burst == 0 {
+            return None;
+        }
+        let pair = (self.source, 0x8000 + self.dest);
+        self.source = self.source.wrapping_add(1);
+        self.dest += 1;
+        self.burst -= 1;
+        if self.burst.is_multiple_of(HDMA_BLOCK_SIZE) {
+            self.blocks -= 1;
+            if self.blocks == 0 {
+                self.hblank_mode = false;
+            }
+        }
+        // Running off the end of vram ends the transfer early
+        if self.dest >= 0x2000 {
+            self.dest &= 0x1FFF;
+            self.burst = 0;
+            self.blocks = 0;
+            self.hblank_mode = false;
+        }
+        Some(pair)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PPU {
+    screen: Screen,
+    colors: Vec<u16>, // 15 bit colors of the screen, only used in CGB mode
+    vram: Vec<u8>, // size of 0x4000, bank 1 is only reachable in CGB mode
+    vram_bank: u8,
+    cgb: bool,
+    bg_palettes: Vec<u8>, // size of 64
+    obj_palettes: Vec<u8>, // size of 64
+    bg_palette_index: u8, // BCPS
+    obj_palette_index: u8, // OCPS
+    registers: Vec<u8>,// size of 16
+    tick: usize,
+    oam_ram: Vec<Sprite>, // size of 40
+    spriteline: Vec<PixelData>,
+    pixel_fifo: VecDeque<PixelData>,
+    pixels_pushed: usize,
+    fetch_state: u8, // dots spent fetching the next tile
+    stall: u8, // dots left where the fifo and fetcher are paused
+    sprite_xs: Vec<u8>, // x of the sprites on this line that haven't been fetched yet
+    penalty_tile: Option<(bool, i16)>, // the last tile a sprite waited on the fetcher in
+    lx: u8, // x in the background map of the next pixel out of the fifo
+    discard: u8, // pixels to drop from the fifo for fine scrolling
+    is_window: bool,
+    window_x: u8, // x in the window map of the next pixel out of the fifo
+    window_line: u8, // only counts lines the window was drawn on
+    window_drawn: bool, // the window was drawn on this line
+    wy_triggered: bool, // LY has matched WY this frame
+    stat_line: bool, // the STAT interrupt line, the interrupt only fires when it goes high
+    first_line: bool, // the first line after the lcd is turned on skips mode 2
+    first_frame: bool, // the first frame after the lcd is turned on isn't displayed
+    blocking: bool, // when false the cpu can always access vram and OAM, for debugging
+    #[serde(skip, default="crate::cpu_recievable::none_recivables")]
+    recievables: Option<Recievables>,
+    pub dma: DMAManager,
+    pub hdma: HDMAManager,
+}
+
+impl Clone for PPU {
+    fn clone(&self) -> Self {
+        Self {
+            screen: self.screen.clone(),
+            colors: self.colors.clone(),
+            vram: self.vram.clone(), // size of 0x4000
+            vram_bank: self.vram_bank,
+            cgb: self.cgb,
+            bg_palettes: self.bg_palettes.clone(),
+            obj_palettes: self.obj_palettes.clone(),
+            bg_palette_index: self.bg_palette_index,
+            obj_palette_index: self.obj_palette_index,
+            registers: self.registers.clone(),// size of 16
+            tick: self.tick,
+            oam_ram: self.oam_ram.clone(),
+            spriteline: self.spriteline.clone(),
+            pixel_fifo: self.pixel_fifo.clone(),
+            pixels_pushed: self.pixels_pushed.clone(),
+            fetch_state: self.fetch_state,
+            stall: self.stall,
+            sprite_xs: self.sprite_xs.clone(),
+            penalty_tile: self.penalty_tile,
+            lx: self.lx,
+            discard: self.discard,
+            is_window: self.is_window,
+            window_x: self.window_x,
+            window_line: self.window_line,
+            window_drawn: self.window_drawn,
+            wy_triggered: self.wy_triggered,
+            stat_line: self.stat_line,
+            first_line: self.first_line,
+            first_frame: self.first_frame,
+            blocking: self.blocking,
+            recievables: None,
+            dma: self.dma.clone(),
+            hdma: self.hdma.clone(),
+        }
+    }
+}
+
+const TICK_WIDTH: usize = 456;
+const OAM_WIDTH: usize = 80;
+// https://gbdev.io/pandocs/#pixel-fifo
+// Getting the tile number, then the low and high bytes of the tile takes 2 dots each
+const FETCH_WIDTH: u8 = 6;
+const EFFECTIVE_SCAN_COUNT: u8 = 153;
+
+const color00: u8 = 0b00;
+const color01: u8 = 0b01;
+const color10: u8 = 0b10;
+const color11: u8 = 0b11;
+
+#[derive(Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize)]
+struct Sprite {
+    pos_x: u8,
+    pos_y: u8,
+    tile: u8,
+    flags: u8,
+}
+
+impl Sprite {
+    const fn new() -> Self {
+        Sprite {
+            pos_x: 0,
+            pos_y: 0,
+            tile:  0,
+            flags: 0,
+        }
+    }
+    fn from_memory(mem: &[u8; 0x2000], loc: usize) -> Self {
+        Sprite {
+            pos_y: mem[loc+0],
+            pos_x: mem[loc+1],
+            tile:  mem[loc+2],
+            flags: mem[loc+3],
+        }
+    }
+
+    // https://gbdev.io/pandocs/#byte3-attributes-flags
+    // Bit7   OBJ-to-BG Priority (0=OBJ Above BG, 1=OBJ Behind BG color 1-3)
+    // Bit6   Y flip          (0=Normal, 1=Vertically mirrored)
+    // Bit5   X flip          (0=Normal, 1=Horizontally mirrored)
+    // Bit4   Palette number  **Non CGB Mode Only** (0=OBP0, 1=OBP1)
+    // Bit3   Tile VRAM-Bank  **CGB Mode Only**     (0=Bank 0, 1=Bank 1)
+    // Bit2-0 Palette number  **CGB Mode Only**     (OBP0-7)
+    fn priority(&self) -> u8 {
+        (self.flags & 0b10000000) >> 7
+    }
+    fn is_x_flipped(&self) -> bool {
+        self.flags & 0b00100000 > 0
+    }
+    fn is_y_flipped(&self) -> bool {
+        self.flags & 0b01000000 > 0
+    }
+    fn palette(&self) -> PixelSrc {
+        if self.flags & 0b00010000 > 0 {
+            PixelSrc::S2
+        } else {
+            PixelSrc::S1
+        }
+    }
+    fn vram_bank(&self) -> usize {
+        ((self.flags & 0b1000) >> 3) as usize
+    }
+    fn cgb_palette(&self) -> u8 {
+        self.flags & 0b111
+    }
+}
+
+#[derive(Clone,Copy,Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+enum PixelSrc {
+    BG, S1, S2
+}
+#[derive(Clone,Copy,Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+struct PixelData {
+    value: u8, // Really this is a 2 bit number
+    src: PixelSrc,
+    palette: u8, // CGB palette number
+    priority: bool, // CGB BG-to-OAM priority for BG pixels, OBJ-to-BG priority for sprites
+}
+
+impl std::fmt::Display for PixelData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self.src {
+            PixelSrc::BG => "BG",
+            PixelSrc::S1 => "S1",
+            PixelSrc::S2 => "S2",
+        };
+        write!(f, "<{}::{:02b}>", s, self.value)
+    }
+}
+
+
+impl PPU {
+    pub fn new() -> Self {
+        let mut ppu =
+            PPU {
+                screen: [color00; SCREEN_WIDTH * SCREEN_HEIGHT].to_vec(),
+                colors: [0x7FFF; SCREEN_WIDTH * SCREEN_HEIGHT].to_vec(),
+                vram: [0u8; VRAM_BANK_SIZE * 2].to_vec(),
+                vram_bank: 0,
+                cgb: false,
+                // The boot rom leaves the palettes white
+                bg_palettes: [0xFF; PALETTE_RAM_SIZE].to_vec(),
+                obj_palettes: [0xFF; PALETTE_RAM_SIZE].to_vec(),
+                bg_palette_index: 0,
+                obj_palette_index: 0,
+                registers: [0u8; 0x10].to_vec(),
+                oam_ram: [Sprite::new(); 40].to_vec(),
+                tick: 0,
+                spriteline: Vec::new(),
+                pixel_fifo: VecDeque::new(),
+                pixels_pushed: 0,
+                fetch_state: 0,
+                stall: 0,
+                sprite_xs: Vec::new(),
+                penalty_tile: None,
+                is_window: false,
+                window_x: 0,
+                window_line: 0,
+                window_drawn: false,
+                wy_triggered: false,
+                stat_line: false,
+                first_line: false,
+                first_frame: false,
+                blocking: true,
+                lx: 0,
+                discard: 0,
+                recievables: None,
+                dma: DMAManager::new(),
+                hdma: HDMAManager::new(),
+            };
+        ppu.registers[LY] = 143;
+        ppu
+    }
+
+    pub fn get_canvas(&self) -> Canvas {
+        // Hex colors need to be in ABGR order for direct loading
+        const color00: u32 = 0xFF0FBC9C;
+        const color01: u32 = 0xFF0FAC8B;
+        const color10: u32 = 0xFF306230;
+        const color11: u32 = 0xFF0F380F;
+        let mut canvas = [0u32; SCREEN_WIDTH * SCREEN_HEIGHT];
+        if self.cgb {
+            for (px, c) in canvas.iter_mut().zip(self.colors.iter()) {
+                *px = color15_to_abgr(*c);
+            }
+            return canvas;
+        }
+        for i in 0..(SCREEN_WIDTH * SCREEN_HEIGHT) {
+            canvas[i] = match self.screen[i] {
+                0b00 => color00,
+                0b01 => color01,
+                0b10 => color10,
+                _ => color11,
+            }
+        }
+        canvas
+
+    }
+
+    pub fn get_screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    // SGB VRAM transfers copy 4KB out of what is on screen, which is the
+    // tile data of the first 256 tiles of the background map, row by row
+    pub fn sgb_transfer_data(&self) -> Vec<u8> {
+        let tilemap_loc = self.tilemap_loc(self.registers[LCD_CONTROL_REGISTER] & 0b00001000) as usize;
+        let mut data = Vec::with_capacity(256 * 16);
+        for i in 0..256 {
+            let tile_idx = self.vram[tilemap_loc + (i / 20) * 32 + i % 20];
+            let loc = if self.registers[LCD_CONTROL_REGISTER] & 0b10000 > 0 {
+                tile_idx as usize * 16
+            } else {
+                ((tile_idx as i8) as isize * 16 + 0x1000) as usize
+            };
+            data.extend_from_slice(&self.vram[loc..loc + 16]);
+        }
+        data
+    }
+
+    fn lookup_color(&self, p: PixelData, old: u8) -> u8 {
+        let palette = match p.src {
+            PixelSrc::BG => self.registers[BGP],
+            PixelSrc::S1 => self.registers[OBP0],
+            PixelSrc::S2 => self.registers[OBP1],
+        };
+        let num = palette >> ((p.value & 0b11) << 1) & 0b11;
+        if match p.src {PixelSrc::BG => false, _ => true} {
+            if p.value == 0 {
+                return old
+            }
+
+        }
+        match num {
+            0b00 => color00,
+            0b01 => color01,
+            0b10 => color10,
+            0b11 => color11,
+            _ => unreachable!("number should be mod 4"),
+        }
+    }
+
+    fn lookup_cgb_color(&self, p: PixelData) -> u16 {
+        let palettes = match p.src {
+            PixelSrc::BG => &self.bg_palettes,
+            _ => &self.obj_palettes,
+        };
+        let loc = (p.palette as usize & 0b111) * 8 + (p.value as usize & 0b11) * 2;
+        (palettes[loc] as u16 | (palettes[loc + 1] as u16) << 8) & 0x7FFF
+    }
+
+    // Picks between the background and sprite pixel in CGB mode. LCDC bit 0
+    // turns off every priority bit rather than the background
+    fn cgb_mix(&self, bg: PixelData, sprite: PixelData) -> PixelData {
+        let master = self.registers[LCD_CONTROL_REGISTER] & 0b1 > 0;
+        if sprite.value == 0 || master && bg.value != 0 && (bg.priority || sprite.priority) {
+            bg
+        } else {
+            sprite
+        }
+    }
+
+    fn tilemap_loc(&self, num: u8) -> u16 {
+        if num > 0 {
+            return 0x1C00 // 9C00 - 8000
+        } else {
+            return 0x1800 // 9800 - 8000
+        }
+
+    }
+    fn get_effective_y(&self) -> u8 {
+        let y = ((self.registers[LY] as u16 + self.registers[SCY] as u16) & 0xFF) as u8;
+        y
+    }
+    // The tile map and the x, y within it of the next tile to fetch. The window
+    // has its own map, and starts from its top left instead of scrolling
+    fn fetch_position(&self) -> (u16, u8, u8) {
+        let queued = self.pixel_fifo.len() as u8;
+        if self.is_window {
+            let tilemap_loc = self.tilemap_loc(self.registers[LCD_CONTROL_REGISTER] & 0b01000000);
+            (tilemap_loc, self.window_x.wrapping_add(queued), self.window_line)
+        } else {
+            let tilemap_loc = self.tilemap_loc(self.registers[LCD_CONTROL_REGISTER] & 0b00001000);
+            (tilemap_loc, self.lx.wrapping_add(queued), self.get_effective_y())
+        }
+    }
+    fn tile_data_loc(&self, tile_idx: u8) -> usize {
+        if self.registers[LCD_CONTROL_REGISTER] & 0b10000 > 0 {
+            return tile_idx as usize * 16;
+        } else {
+            return ((tile_idx as i8) as isize * 16 + 0x1000) as usize;
+        }
+    }
+    fn sprite_tile_loc(&self, idx: u8) -> usize {
+        idx as usize * 16
+    }
+
+    // The sprites on this line, in OAM order
+    fn line_sprites(&self) -> Vec<Sprite> {
+        if self.registers[LCD_CONTROL_REGISTER] & 0b10 == 0 {
+            return Vec::new();
+        }
+        let s_size = if self.registers[LCD_CONTROL_REGISTER] & 0b100 > 0 { 16 } else { 8 };
+        let ly = self.registers[LY] as usize;
+        // Sprites off the side of the screen still count towards the limit of 10
+        self.oam_ram.iter()
+            .filter(|sj| ly + 16 >= sj.pos_y as usize && ly + 16 < sj.pos_y as usize + s_size)
+            .take(10)
+            .copied()
+            .collect()
+    }
+
+    // https://gbdev.io/pandocs/#mode-3-length
+    // Fetching a sprite pauses the fifo for 6 dots, plus however long it has to
+    // wait for the background fetcher to finish the tile the sprite starts in.
+    // Only the first sprite in each tile has to wait
+    fn sprite_penalty(&mut self) -> u8 {
+        let x = self.pixels_pushed as i16;
+        let mut penalty = 0;
+        while let Some(&pos_x) = self.sprite_xs.first() {
+            let sx = pos_x as i16 - 8;
+            if sx > x {
+                break;
+            }
+            self.sprite_xs.remove(0);
+            if pos_x == 0 {
+                penalty += 11; // regardless of the scroll
+                continue;
+            }
+            // Where the leftmost pixel of the sprite is in the background or window
+            let next = if self.is_window { self.window_x } else { self.lx } as i16;
+            let bx = next + sx - x;
+            let tile = (self.is_window, bx.div_euclid(8));
+            if self.penalty_tile != Some(tile) {
+                self.penalty_tile = Some(tile);
+                let right = 7 - bx.rem_euclid(8);
+                penalty += (right - 2).max(0) as u8;
+            }
+            penalty += 6;
+        }
+        penalty
+    }
+
+    // Finds the first 10 sprites on this line in OAM order and draws them into
+    // a line of sprite pixels. On DMG the sprite with the smaller X is drawn on top,
+    // in CGB mode it is the one earlier in OAM. Transparent pixels never cover anything
+    fn scan_sprites(&self) -> Vec<PixelData> {
+        let mut data = [PixelData {
+            src: PixelSrc::S1,
+            value: 0b00,
+            palette: 0,
+            priority: false,
+        }; 168];
+        if self.registers[LCD_CONTROL_REGISTER] & 0b10 == 0 {
+            return data[8..].to_vec();
+        }
+        let tall = self.registers[LCD_CONTROL_REGISTER] & 0b100 > 0;
+        let s_size = if tall { 16 } else { 8 };
+        let ly = self.registers[LY] as usize;
+
+        let mut sprites = self.line_sprites();
+        if !self.cgb {
+            sprites.sort_by_key(|sj| sj.pos_x); // stable, so ties go to the first in OAM
+        }
+
+        for sj in sprites.iter() {
+            let mut ey = ly + 16 - sj.pos_y as usize;
+            if sj.is_y_flipped() {
+                ey = s_size - 1 - ey;
+            }
+            // 8x16 sprites ignore bit 0 of the tile number, the bottom half is the next tile
+            let tile = if tall { (sj.tile & 0xFE) | (ey / 8) as u8 } else { sj.tile };
+            let bank = if self.cgb { sj.vram_bank() * VRAM_BANK_SIZE } else { 0 };
+            let mut sp = self.decode_tile(bank + self.sprite_tile_loc(tile), ey % 8);
+            if sj.is_x_flipped() {
+                sp.reverse();
+            }
+            for (i, p) in sp.iter().enumerate() {
+                let tx = sj.pos_x as usize + i;
+                if p.value != 0 && tx < 168 && data[tx].value == 0 {
+                    data[tx] = PixelData {
+                        src: if self.cgb { PixelSrc::S1 } else { sj.palette() },
+                        value: p.value,
+                        palette: if self.cgb { sj.cgb_palette() } else { 0 },
+                        priority: sj.priority() > 0,
+                    };
+                }
+            }
+        }
+        data[8..].to_vec()
+    }
+
+    fn decode_tile(&self, loc: usize, line: usize) -> [PixelData; 8] {
+        let vloc = loc + line * 2;
+        let bg_tile_low = self.vram[vloc];
+        let bg_tile_high = self.vram[vloc + 1];
+        let gen = move |i: usize| {
+            let bh = (bg_tile_high >> (7 - i)) & 1;
+            let bl = (bg_tile_low >> (7 - i)) & 1;
+            bh << 1 | bl
+        };
+        let mut v = [PixelData{value: 0, src: PixelSrc::BG, palette: 0, priority: false}; 8];
+        for i in 0..v.len() {
+            v[i].value = gen(i)
+        }
+        // println!("Tile loc: {:04X} on line {}. Effective y is {}. Decoded to {:?}",
+        //  loc + 0x8000, line,
+        //  (self.registers[LY] as usize + self.registers[SCY] as usize) & 0xFF,
+        //  v
+        // );
+        v
+
+    }
+
+    // The fetcher spends FETCH_WIDTH dots on a tile, then waits until the fifo is
+    // empty to push the 8 pixels
+    fn fetch(&mut self) -> Option<[PixelData; 8]> {
+        if self.fetch_state < FETCH_WIDTH {
+            self.fetch_state += 1;
+        }
+        if self.fetch_state == FETCH_WIDTH && self.pixel_fifo.is_empty() {
+            self.fetch_state = 0;
+            let (tilemap_loc, x, y) = self.fetch_position();
+            let map_loc = tilemap_loc as usize + (y as usize) / 8 * 32 + (x as usize) / 8;
+            let attr = if self.cgb { self.vram[VRAM_BANK_SIZE + map_loc] } else { 0 };
+            let loc = self.tile_data_loc(self.vram[map_loc]) + if attr & 0b1000 > 0 { VRAM_BANK_SIZE } else { 0 };
+            let line = if attr & 0b1000000 > 0 { 7 - (y & 0b111) } else { y & 0b111 };
+            let mut px = self.decode_tile(loc, line as usize);
+            if attr & 0b100000 > 0 {
+                px.reverse();
+            }
+            for p in px.iter_mut() {
+                p.palette = attr & 0b111;
+                p.priority = attr & 0b10000000 > 0;
+            }
+            Some(px)
+        } else {
+            None
+        }
+    }
+
+    fn sendif(&mut self, i: Interrupt) {
+        match &self.recievables {
+            Some(r) => r.send(SendInterrupt(i)),
+            None => ()
+        }
+    }
+    // https://gbdev.io/pandocs/#int-48-stat-interrupt
+    // All the enabled STAT sources are or'd together into one line, and the interrupt
+    // is only requested when that line goes from low to high. So if one source is
+    // still active when another one starts, no new interrupt is requested
+    fn update_stat(&mut self) {
+        let stat = self.registers[LCD_STATUS_REGISTER];
+        let coincidence = self.registers[LY] == self.registers[LYC];
+        if coincidence {
+            self.registers[LCD_STATUS_REGISTER] |= 0b100;
+        } else {
+            self.registers[LCD_STATUS_REGISTER] &= !0b100;
+        }
+
+        let line = (stat & 0b01000000 > 0 && coincidence) || match self.get_mode() {
+            Mode::HBlank => stat & 0b00001000 > 0,
+            Mode::VBlank => stat & 0b00010000 > 0,
+            Mode::OAM => stat & 0b00100000 > 0,
+            Mode::VRAM => false,
+        };
+        // Nothing is requested while the lcd is off
+        let line = line && self.is_lcd_enabled();
+        if line && !self.stat_line {
+            self.sendif(Interrupt::LCDStat);
+        }
+        self.stat_line = line;
+    }
+    fn is_lcd_enabled(&self) -> bool {
+        self.registers[LCD_CONTROL_REGISTER] & 0b10000000 > 0
+    }
+    // https://gbdev.io/pandocs/#lcdc7-lcd-display-enable
+    // While the lcd is off LY stays at 0 in mode 0, and the screen is blank
+    fn lcd_off(&mut self) {
+        self.registers[LY] = 0;
+        self.set_mode(Mode::HBlank);
+        self.tick = 0;
+        self.pixel_fifo.clear();
+        self.pixels_pushed = 0;
+        self.lx = 0;
+        self.is_window = false;
+        self.window_drawn = false;
+        self.window_line = 0;
+        self.wy_triggered = false;
+        self.stat_line = false;
+        for px in self.screen.iter_mut() {
+            *px = color00;
+        }
+        for c in self.colors.iter_mut() {
+            *c = 0x7FFF;
+        }
+    }
+    // Turning the lcd back on starts a new frame, but the first line starts in
+    // mode 0 instead of mode 2, and nothing is shown until the next frame
+    fn lcd_on(&mut self) {
+        self.registers[LY] = 0;
+        self.set_mode(Mode::HBlank);
+        self.tick = 0;
+        self.first_line = true;
+        self.first_frame = true;
+        self.update_stat();
+    }
+    fn start_vram(&mut self) {
+        // OAM lookup, this is normally done over 20 dots, but we'll just do it at the end
+        self.spriteline = self.scan_sprites();
+        if self.registers[LY] == self.registers[WY] {
+            self.wy_triggered = true;
+        }
+        let mut xs: Vec<u8> = self.line_sprites().iter().map(|sj| sj.pos_x).filter(|x| *x < 168).collect();
+        xs.sort_unstable();
+        self.sprite_xs = xs;
+        self.penalty_tile = None;
+        // Whole tiles of SCX are skipped by the fetcher, the rest is dropped from the fifo
+        self.lx = self.registers[SCX] & !0b111;
+        self.discard = self.registers[SCX] & 0b111;
+        // The first tile is fetched twice, so mode 3 is at least 172 dots
+        self.stall = FETCH_WIDTH;
+        self.fetch_state = 0;
+        self.set_mode(Mode::VRAM);
+    }
+    pub fn tick(&mut self) {
+        if !self.is_lcd_enabled() {
+            return;
+        }
+        self.tick += 1;
+        match self.get_mode() {
+            Mode::HBlank if self.first_line => {
+                if self.tick == OAM_WIDTH {
+                    self.first_line = false;
+                    self.start_vram();
+                }
+            },
+            Mode::HBlank => {
+                if self.tick >= TICK_WIDTH {
+                    self.registers[LY] += 1;
+                    if self.registers[LY] >= 144 {
+                        self.sendif(Interrupt::VBlank);
+                        self.set_mode(Mode::VBlank);
+                        self.first_frame = false;
+                        self.window_line = 0;
+                        self.wy_triggered = false;
+                    } else {
+                        self.set_mode(Mode::OAM);
+                    }
+                    self.tick = 0;
+                }
+            },
+            Mode::VBlank => {
+                if self.tick >= TICK_WIDTH {
+                    self.registers[LY] += 1;
+                    if self.registers[LY] > EFFECTIVE_SCAN_COUNT {
+                        self.registers[LY] = 0;
+                        self.set_mode(Mode::OAM);
+                    }
+                    self.tick = 0;
+                }
+            },
+            Mode::OAM => {
+                if self.tick == 1 {
+                } else if self.tick == OAM_WIDTH {
+                    self.start_vram();
+                }
+            },
+            // The fifo and fetcher are paused
+            Mode::VRAM if self.stall > 0 => self.stall -= 1,
+            Mode::VRAM => {
+                // render a pixel
+                // https://gbdev.io/pandocs/#mode-3-length
+                // The fifo pushes a pixel every dot while it has any, and the fetcher
+                // refills it when it runs out. So mode 3 takes 172 dots, plus a dot for
+                // each pixel dropped for SCX, plus the time to restart the fetcher for
+                // the window and to fetch sprites. HBlank is shortened to make up for it
+                //
+                // When we hit window, the fifo is cleared, and the fetch switches to window
+                // https://gbdev.io/pandocs/#ff4a-wy-window-y-position-r-w-ff4b-wx-window-x-position-7-r-w
+                // The window starts at WX-7, once LY has reached WY at some point this frame
+                let window_enabled = self.registers[LCD_CONTROL_REGISTER] & 0b100000 > 0;
+                let wx = self.registers[WX] as usize;
+                if !self.is_window && window_enabled && self.wy_triggered && self.pixels_pushed + 7 >= wx {
+                    self.is_window = true;
+                    self.window_drawn = true;
+                    self.window_x = 0;
+                    // A WX below 7 pushes the start of the window off the left edge
+                    self.discard = 7u8.saturating_sub(wx as u8);
+                    self.pixel_fifo.clear();
+                    self.fetch_state = 0;
+                } else if self.is_window && !window_enabled {
+                    self.is_window = false;
+                    self.pixel_fifo.clear();
+                    self.fetch_state = 0;
+                }
+
+                let penalty = if self.discard == 0 && !self.pixel_fifo.is_empty() { self.sprite_penalty() } else { 0 };
+                if penalty > 0 {
+                    self.stall = penalty - 1; // this is the first dot of it
+                } else if let Some(p) = self.pixel_fifo.pop_front() {
+                    let y = self.registers[LY];
+                    let x = self.pixels_pushed;
+                    let old_color = self.screen[(x as usize) + (y as usize) * SCREEN_WIDTH];
+                    let color_bg = self.lookup_color(p, old_color);
+                    let sprite = *self.spriteline.get(x).unwrap();
+                    // Sprites with priority only show over BG color 0
+                    let color = if sprite.priority && p.value != 0 {
+                        color_bg
+                    } else {
+                        self.lookup_color(sprite, color_bg)
+                    };
+                    //println!("Pixel {}: (x, y)[{},{}] -> Color: {:X}", p, x, y, color);
+                    if self.discard > 0 {
+                        self.discard -= 1;
+                    } else if self.first_frame {
+                        self.pixels_pushed += 1;
+                    } else {
+                        let idx = (x as usize) + (y as usize) * SCREEN_WIDTH;
+                        if self.cgb {
+                            let c = self.lookup_cgb_color(self.cgb_mix(p, self.spriteline[x]));
+                            self.colors[idx] = c;
+                            self.screen[idx] = color15_to_shade(c);
+                        } else {
+                            self.screen[idx] = color;
+                        }
+                        self.pixels_pushed += 1;
+                    }
+                    if self.is_window {
+                        self.window_x = self.window_x.wrapping_add(1);
+                    } else {
+                        self.lx = self.lx.wrapping_add(1);
+                    }
+                }
+
+                let new_pixels = self.fetch();
+                if let Some(px) = new_pixels {
+                    for p in px.iter() {
+                        self.pixel_fifo.push_back(*p);
+                    }
+                }
+
+                if self.pixels_pushed >= 160 {
+                    self.pixels_pushed = 0;
+                    self.lx = 0;
+                    self.is_window = false;
+                    if self.window_drawn {
+                        self.window_drawn = false;
+                        self.window_line += 1;
+                    }
+                    self.set_mode(Mode::HBlank);
+                    self.hdma.hblank();
+                    self.pixel_fifo.clear();
+                }
+            },
+        }
+        self.update_stat();
+    }
+    fn set_mode(&mut self, mode: Mode) {
+        let v = match mode {
+            Mode::HBlank => 0b00,
+            Mode::VBlank => 0b01,
+            Mode::OAM => 0b10,
+            Mode::VRAM => 0b11,
+        };
+
+        self.registers[LCD_STATUS_REGISTER] &= 0b11111100;
+        self.registers[LCD_STATUS_REGISTER] |= v;
+    }
+    fn get_mode(&self) -> Mode {
+        match self.registers[LCD_STATUS_REGISTER] & 0b11 {
+            0b00 => Mode::HBlank,
+            0b01 => Mode::VBlank,
+            0b10 => Mode::OAM,
+            0b11 => Mode::VRAM,
+            _ => unreachable!("exhaustive match pattern")
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        if !cgb {
+            self.vram_bank = 0;
+        }
+    }
+
+    // https://gbdev.io/pandocs/#accessing-vram-and-oam
+    // The cpu can't use vram while the ppu is drawing (mode 3), or OAM while the
+    // ppu is scanning or drawing (mode 2 and 3). Blocked reads give 0xFF and
+    // blocked writes are ignored. With the lcd off everything is accessible
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking
+    }
+    fn is_vram_blocked(&self) -> bool {
+        self.blocking && self.get_mode() == Mode::VRAM
+    }
+    fn is_oam_blocked(&self) -> bool {
+        self.blocking && (self.get_mode() == Mode::VRAM || self.get_mode() == Mode::OAM)
+    }
+    // The cpu is restricted to HRAM while OAM DMA is copying
+    pub fn is_dma_blocking(&self) -> bool {
+        self.blocking && self.dma.is_active()
+    }
+
+    // Between frames, or not drawing any at all
+    pub fn is_in_vblank(&self) -> bool {
+        !self.is_lcd_enabled() || self.get_mode() == Mode::VBlank
+    }
+
+    // Both read and write expect loc to be in the address range 0x8000..=0x9FFF
+    pub fn write(&mut self, loc: u16, val: u8) {
+        // print!("PPU Write: [{:04X}] = {:02X}. During mode {:?}\n", loc, val, self.get_mode());
+        if !self.is_vram_blocked() {
+            self.dma_write(loc, val);
+        }
+    }
+    pub fn read(&self, loc: u16) -> u8 {
+        if self.is_vram_blocked() {
+            return 0xFF;
+        }
+        let l = loc as usize - 0x8000 + self.vram_bank as usize * VRAM_BANK_SIZE;
+        self.vram[l]
+    }
+    // HDMA writes to vram no matter what mode the ppu is in
+    pub fn dma_write(&mut self, loc: u16, val: u8) {
+        let l = loc as usize - 0x8000 + self.vram_bank as usize * VRAM_BANK_SIZE;
+        self.vram[l] = val;
+    }
+    pub fn writeOAM(&mut self, loc: u16, val: u8) {
+        if !self.is_oam_blocked() {
+            self.dma_write_oam(loc, val);
+        }
+    }
+    // OAM DMA writes to OAM no matter what mode the ppu is in
+    pub fn dma_write_oam(&mut self, loc: u16, val: u8) {
+        let l = (loc as usize - 0xFE00) / 4;
+        match loc & 0b11 {
+            0 => self.oam_ram[l].pos_y = val,
+            1 => self.oam_ram[l].pos_x = val,
+            2 => self.oam_ram[l].tile = val,
+            3 => self.oam_ram[l].flags = val,
+            _ => unreachable!("exhaustive match pattern")
+        }
+    }
+    pub fn readOAM(&self, loc: u16) -> u8 {
+        if self.is_oam_blocked() {
+            return 0xFF;
+        }
+        let l = (loc as usize - 0xFE00) / 4;
+        match loc & 0b11 {
+            0 => self.oam_ram[l].pos_y,
+            1 => self.oam_ram[l].pos_x,
+            2 => self.oam_ram[l].tile,
+            3 => self.oam_ram[l].flags,
+            _ => unreachable!("exhaustive match pattern")
+        }
+    }
+
+    pub fn write_reg(&mut self, loc: u16, val: u8) {
+        match loc {
+            VBK if self.cgb => return self.vram_bank = val & 0b1,
+            BCPS if self.cgb => return self.bg_palette_index = val & 0b10111111,
+            OCPS if self.cgb => return self.obj_palette_index = val & 0b10111111,
+            BCPD if self.cgb => {
+                self.bg_palettes[self.bg_palette_index as usize & 0x3F] = val;
+                self.bg_palette_index = next_palette_index(self.bg_palette_index);
+                return;
+            }
+            OCPD if self.cgb => {
+                self.obj_palettes[self.obj_palette_index as usize & 0x3F] = val;
+                self.obj_palette_index = next_palette_index(self.obj_palette_index);
+                return;
+            }
+            0xFF4C..=0xFF4F | BCPS..=OCPD => return, // CGB registers outside of CGB mode
+            _ => (),
+        }
+        let l = loc as usize - 0xFF40;
+        if l == LCD_CONTROL_REGISTER {
+            let was_enabled = self.is_lcd_enabled();
+            self.registers[l] = val;
+            match (was_enabled, self.is_lcd_enabled()) {
+                (true, false) => self.lcd_off(),
+                (false, true) => self.lcd_on(),
+                _ => (),
+            }
+            return;
+        }
+        if l == LCD_STATUS_REGISTER {
+            // The mode and coincidence flag are read only
+            self.registers[l] = val & 0b01111000 | self.registers[l] & 0b111;
+        } else {
+            self.registers[l] = val;
+        }
+        if l == DMA {
+            self.dma.start_transfer(val);
+        } else if l == LY {
+            panic!("0xFF44 is read only")
+        } else if l == LYC || l == LCD_STATUS_REGISTER {
+            self.update_stat();
+        }
+    }
+    pub fn read_reg(&self, loc: u16) -> u8 {
+        match loc {
+            VBK if self.cgb => return 0b11111110 | self.vram_bank,
+            BCPS if self.cgb => return 0b01000000 | self.bg_palette_index,
+            OCPS if self.cgb => return 0b01000000 | self.obj_palette_index,
+            BCPD if self.cgb => return self.bg_palettes[self.bg_palette_index as usize & 0x3F],
+            OCPD if self.cgb => return self.obj_palettes[self.obj_palette_index as usize & 0x3F],
+            BCPS..=OCPD => return 0xFF,
+            _ => (),
+        }
+        let l = loc as usize - 0xFF40;
+        if l > 0xB {
+            //panic!("CGB functionallity is not supported")
+                return 0xff
+        }
+        if l == LCD_STATUS_REGISTER {
+            return 0b10000000 | self.registers[l]; // bit 7 is unused
+        }
+        self.registers[l]
+    }
+
+    pub fn set_recievables(&mut self, recievables: Recievables) {
+        self.recievables = Some(recievables)
+    }
+}
+
+fn next_palette_index(index: u8) -> u8 {
+    if index & 0b10000000 > 0 {
+        0b10000000 | (index + 1) & 0x3F
+    } else {
+        index
+    }
+}
+
+fn color15_channels(c: u16) -> (u32, u32, u32) {
+    ((c & 0x1F) as u32, (c >> 5 & 0x1F) as u32, (c >> 10 & 0x1F) as u32)
+}
+
+// Scales each 5 bit channel up to 8 bits, in ABGR order like get_canvas
+pub(crate) fn color15_to_abgr(c: u16) -> u32 {
+    let (r, g, b) = color15_channels(c);
+    let scale = |x: u32| x << 3 | x >> 2;
+    0xFF000000 | scale(b) << 16 | scale(g) << 8 | scale(r)
+}
+
+// Approximates a CGB color with the DMG shades, so get_screen still works in CGB mode
+fn color15_to_shade(c: u16) -> u8 {
+    let (r, g, b) = color15_channels(c);
+    let brightness = (r + g + b) / 3; // 0-31
+    3 - (brightness / 8) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_test_ppu() -> PPU {
+        PPU::new()
+    }
+
+    #[test]
+    fn test_pixel_color_lookup () {
+        let mut ppu = create_test_ppu();
+
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[OBP0] = 0b11100100;
+        assert_eq!(color00, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b00,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color01, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b01,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color10, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b10,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color11, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b11,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+
+        ppu.registers[BGP] = 0b10110001;
+        assert_eq!(color01, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b00,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color00, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b01,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color11, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b10,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+        assert_eq!(color10, ppu.lookup_color(PixelData{
+            src: PixelSrc::BG,
+            value: 0b11,
+            palette: 0,
+            priority: false,
+        }, 0b00));
+
+    }
+
+    #[test]
+    fn test_tile_decode () {
+        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
+                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
+        ppu.lx = 0;
+        ppu.registers[LY] = 0;
+        for i in 0..testtile.len(){
+            ppu.vram[i+16] = testtile[i];
+        }
+
+        for i in 0..(32*32) {
+            ppu.vram[0x1800 + i] = 1;
+        }
+        for i in 0..(FETCH_WIDTH - 1) {
+            assert_eq!(None, ppu.fetch())
+        }
+        let p00 = PixelData{src: PixelSrc::BG, value: 0b00, palette: 0, priority: false};
+        let p01 = PixelData{src: PixelSrc::BG, value: 0b01, palette: 0, priority: false};
+        let p10 = PixelData{src: PixelSrc::BG, value: 0b10, palette: 0, priority: false};
+        let p11 = PixelData{src: PixelSrc::BG, value: 0b11, palette: 0, priority: false};
+        assert_eq!(Some([p00, p00, p00, p00, p01, p01, p01, p01]), ppu.fetch());
+
+        ppu.lx = 0;
+        ppu.registers[LY] = 1;
+        for i in 0..(FETCH_WIDTH - 1) {
+            assert_eq!(None, ppu.fetch())
+        }
+        assert_eq!(Some([p00, p00, p00, p00, p01, p01, p01, p01]), ppu.fetch());
+
+        ppu.lx = 0;
+        ppu.registers[LY] = 4;
+        for i in 0..(FETCH_WIDTH - 1) {
+            assert_eq!(None, ppu.fetch())
+        }
+        assert_eq!(Some([p10, p10, p10, p10, p11, p11, p11, p11]), ppu.fetch());
+
+        ppu.lx = 0;
+        ppu.registers[LY] = 0;
+        ppu.registers[SCY] = 6;
+        for i in 0..(FETCH_WIDTH - 1) {
+            assert_eq!(None, ppu.fetch())
+        }
+        assert_eq!(Some([p10, p10, p10, p10, p11, p11, p11, p11]), ppu.fetch());
+    }
+
+    #[test]
+    fn test_ppu_tick () {
+        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
+                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+        let offset = 9;
+        for i in 0..(TICK_WIDTH+1) {
+            ppu.tick();
+        }
+        ppu.registers[LY] = 0;
+        ppu.registers[SCY] = 16;
+        assert_eq!(ppu.get_mode(), Mode::VBlank);
+        for i in 0..testtile.len(){
+            ppu.vram[i+offset*16] = testtile[i];
+        }
+        for i in 0..(32*32) {
+            ppu.vram[0x1800 + i] = offset as u8;
+        }
+        for _ in 0..(80 + 250) {
+            ppu.tick();
+        }
+        assert_eq!(ppu.get_mode(), Mode::VBlank);
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+
+        let line = 144 - 8;
+        for j in line..(line+8) {
+            for i in 0..8{
+                print!("{:X} ", ppu.screen[SCREEN_WIDTH*j + i]);
+            }
+            println!();
+        }
+
+        for j in 0..SCREEN_HEIGHT {
+            for i in 0..SCREEN_WIDTH {
+                let should_color =
+                    if (i / 4) % 2 == 0 {
+                        if (j / 4) % 2 == 0 {
+                            color00
+                        } else {
+                            color10
+                        }
+                    } else {
+                        if (j / 4) % 2 == 0 {
+                            color01
+                        } else {
+                            color11
+                        }
+                    };
+                //println!("Checking ({}, {}) to be {}", i, j, format!("{:X}", should_color));
+                assert_eq!(format!("{:X}", ppu.screen[SCREEN_WIDTH * j + i]), format!("{:X}", should_color));
+                // assert_eq!(ppu.screen[i], should_color);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ppu_tick_scroll () {
+        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
+                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
+        ppu.registers[SCX] = 1;
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+        let offset = 9;
+        for i in 0..(TICK_WIDTH+1) {
+            ppu.tick();
+        }
+        ppu.registers[LY] = 0;
+        ppu.registers[SCY] = 16;
+        assert_eq!(ppu.get_mode(), Mode::VBlank);
+        for i in 0..testtile.len(){
+            ppu.vram[i+offset*16] = testtile[i];
+        }
+        for i in 0..(32*32) {
+            ppu.vram[0x1800 + i] = offset as u8;
+        }
+        for _ in 0..(80 + 250) {
+            ppu.tick();
+        }
+        assert_eq!(ppu.get_mode(), Mode::VBlank);
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+
+        let line = 144 - 8;
+        for j in line..(line+8) {
+            for i in 0..8{
+                print!("{:X} ", ppu.screen[SCREEN_WIDTH*j + i]);
+            }
+            println!();
+        }
+
+        for j in 0..SCREEN_HEIGHT {
+            for i in 0..SCREEN_WIDTH {
+                let should_color =
+                    if ((i+1) / 4) % 2 == 0 {
+                        if (j / 4) % 2 == 0 {
+                            color00
+                        } else {
+                            color10
+                        }
+                    } else {
+                        if (j / 4) % 2 == 0 {
+                            color01
+                        } else {
+                            color11
+                        }
+                    };
+                //println!("Checking ({}, {}) to be {}", i, j, format!("{:X}", should_color));
+                assert_eq!(format!("{:X}", ppu.screen[SCREEN_WIDTH * j + i]), format!("{:X}", should_color));
+                // assert_eq!(ppu.screen[i], should_color);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cgb_palette_registers () {
+        let mut ppu = create_test_ppu();
+        ppu.set_cgb_mode(true);
+
+        // Auto increment wraps around the 64 bytes
+        ppu.write_reg(BCPS, 0b10111110);
+        ppu.write_reg(BCPD, 0x1F);
+        ppu.write_reg(BCPD, 0x00);
+        ppu.write_reg(BCPD, 0xE0);
+        assert_eq!(ppu.read_reg(BCPS), 0b11000001);
+        assert_eq!(ppu.bg_palettes[0x3E], 0x1F);
+        assert_eq!(ppu.bg_palettes[0x3F], 0x00);
+        assert_eq!(ppu.bg_palettes[0x00], 0xE0);
+
+        // Without auto increment the index stays put
+        ppu.write_reg(OCPS, 0x08);
+        ppu.write_reg(OCPD, 0x12);
+        ppu.write_reg(OCPD, 0x34);
+        assert_eq!(ppu.read_reg(OCPS), 0b01001000);
+        assert_eq!(ppu.read_reg(OCPD), 0x34);
+
+        let p = PixelData{src: PixelSrc::BG, value: 0b11, palette: 7, priority: false};
+        assert_eq!(0x001F, ppu.lookup_cgb_color(p));
+        assert_eq!(0xFF0000FF, color15_to_abgr(0x001F));
+        assert_eq!(0xFFFFFFFF, color15_to_abgr(0x7FFF));
+
+        // The registers are locked outside of CGB mode
+        ppu.set_cgb_mode(false);
+        ppu.write_reg(BCPS, 0x00);
+        assert_eq!(ppu.read_reg(BCPS), 0xFF);
+    }
+
+    #[test]
+    fn test_cgb_tile_attributes () {
+        let testtile = [0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00, 0x0F, 0x00,
+                        0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF, 0x0F, 0xFF];
+        let mut ppu = create_test_ppu();
+        ppu.set_cgb_mode(true);
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010000;
+        ppu.lx = 0;
+        ppu.registers[LY] = 0;
+        // Tile 1 only exists in bank 1
+        for i in 0..testtile.len(){
+            ppu.vram[VRAM_BANK_SIZE + i + 16] = testtile[i];
+        }
+        for i in 0..(32*32) {
+            ppu.vram[0x1800 + i] = 1;
+            ppu.vram[VRAM_BANK_SIZE + 0x1800 + i] = 0b11101101; // priority, flips, bank 1, palette 5
+        }
+        for _ in 0..(FETCH_WIDTH - 1) {
+            assert_eq!(None, ppu.fetch())
+        }
+        let p = |value| PixelData{src: PixelSrc::BG, value, palette: 5, priority: true};
+        assert_eq!(Some([p(0b11), p(0b11), p(0b11), p(0b11), p(0b10), p(0b10), p(0b10), p(0b10)]), ppu.fetch());
+
+        // Sprites only show over a BG pixel with priority when its color is 0
+        let sprite = PixelData{src: PixelSrc::S1, value: 0b01, palette: 0, priority: false};
+        ppu.registers[LCD_CONTROL_REGISTER] |= 0b1;
+        assert_eq!(ppu.cgb_mix(p(0b11), sprite), p(0b11));
+        assert_eq!(ppu.cgb_mix(p(0b00), sprite), sprite);
+        // LCDC bit 0 off puts sprites on top of everything
+        ppu.registers[LCD_CONTROL_REGISTER] &= !0b1;
+        assert_eq!(ppu.cgb_mix(p(0b11), sprite), sprite);
+    }
+
+    fn render_frame(ppu: &mut PPU) {
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+    }
+
+    fn set_sprite(ppu: &mut PPU, idx: usize, x: usize, y: usize, tile: u8, flags: u8) {
+        ppu.oam_ram[idx] = Sprite {
+            pos_x: (x + 8) as u8,
+            pos_y: (y + 16) as u8,
+            tile,
+            flags,
+        };
+    }
+
+    // Tile 2 has color 3 in the top left, tile 3 color 1 in the bottom left,
+    // tile 4 is a line of color 3 and tile 5 a line of color 1
+    fn create_sprite_test_ppu() -> PPU {
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[OBP0] = 0b11100100;
+        ppu.registers[OBP1] = 0b01000000;
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010011;
+        ppu.vram[2 * 16] = 0x80;
+        ppu.vram[2 * 16 + 1] = 0x80;
+        ppu.vram[3 * 16 + 14] = 0x80;
+        ppu.vram[4 * 16] = 0xFF;
+        ppu.vram[4 * 16 + 1] = 0xFF;
+        ppu.vram[5 * 16] = 0xFF;
+        ppu
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.screen[y * SCREEN_WIDTH + x]
+    }
+
+    #[test]
+    fn test_sprite_flags () {
+        let sprite = Sprite { pos_x: 0, pos_y: 0, tile: 0, flags: 0b10000000 };
+        assert_eq!(sprite.priority(), 1);
+        assert_eq!(sprite.is_x_flipped(), false);
+        assert_eq!(sprite.is_y_flipped(), false);
+        assert_eq!(sprite.palette(), PixelSrc::S1);
+        let sprite = Sprite { pos_x: 0, pos_y: 0, tile: 0, flags: 0b01111111 };
+        assert_eq!(sprite.priority(), 0);
+        assert_eq!(sprite.is_x_flipped(), true);
+        assert_eq!(sprite.is_y_flipped(), true);
+        assert_eq!(sprite.palette(), PixelSrc::S2);
+        assert_eq!(sprite.vram_bank(), 1);
+        assert_eq!(sprite.cgb_palette(), 7);
+        let sprite = Sprite { pos_x: 0, pos_y: 0, tile: 0, flags: 0b00100000 };
+        assert_eq!(sprite.is_x_flipped(), true);
+        assert_eq!(sprite.is_y_flipped(), false);
+    }
+
+    #[test]
+    fn test_ppu_sprites () {
+        let mut ppu = create_sprite_test_ppu();
+        set_sprite(&mut ppu, 0, 20, 10, 2, 0);
+        set_sprite(&mut ppu, 1, 40, 30, 2, 0b00100000); // x flip
+        set_sprite(&mut ppu, 2, 40, 50, 2, 0b01000000); // y flip
+        set_sprite(&mut ppu, 3, 40, 70, 2, 0b00010000); // OBP1
+
+        // Behind the BG, which is color 1 at (80, 112) and color 0 at (96, 112)
+        ppu.vram[1 * 16] = 0xFF;
+        ppu.vram[0x1800 + 14 * 32 + 10] = 1;
+        set_sprite(&mut ppu, 4, 80, 112, 2, 0b10000000);
+        set_sprite(&mut ppu, 5, 96, 112, 2, 0b10000000);
+
+        // The smaller X is drawn on top, even though it is later in OAM
+        set_sprite(&mut ppu, 6, 121, 130, 5, 0);
+        set_sprite(&mut ppu, 7, 120, 130, 4, 0);
+
+        // Only 10 sprites per line
+        for i in 0..11 {
+            set_sprite(&mut ppu, 8 + i, i * 10, 140, 2, 0);
+        }
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 20, 10), color11);
+        assert_eq!(pixel(&ppu, 21, 10), color00);
+        assert_eq!(pixel(&ppu, 20, 11), color00);
+
+        assert_eq!(pixel(&ppu, 40, 30), color00);
+        assert_eq!(pixel(&ppu, 47, 30), color11);
+
+        assert_eq!(pixel(&ppu, 40, 50), color00);
+        assert_eq!(pixel(&ppu, 40, 57), color11);
+
+        assert_eq!(pixel(&ppu, 40, 70), color01);
+
+        assert_eq!(pixel(&ppu, 80, 112), color01);
+        assert_eq!(pixel(&ppu, 96, 112), color11);
+
+        assert_eq!(pixel(&ppu, 120, 130), color11);
+        assert_eq!(pixel(&ppu, 127, 130), color11);
+        assert_eq!(pixel(&ppu, 128, 130), color01);
+
+        for i in 0..10 {
+            assert_eq!(pixel(&ppu, i * 10, 140), color11);
+        }
+        assert_eq!(pixel(&ppu, 100, 140), color00);
+    }
+
+    #[test]
+    fn test_ppu_tall_sprites () {
+        let mut ppu = create_sprite_test_ppu();
+        ppu.registers[LCD_CONTROL_REGISTER] |= 0b100;
+        set_sprite(&mut ppu, 0, 20, 10, 3, 0); // bit 0 of the tile is ignored
+        set_sprite(&mut ppu, 1, 40, 10, 2, 0b01000000); // y flip swaps the tiles
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 20, 10), color11);
+        assert_eq!(pixel(&ppu, 20, 17), color00);
+        assert_eq!(pixel(&ppu, 20, 25), color01);
+
+        assert_eq!(pixel(&ppu, 40, 10), color01);
+        assert_eq!(pixel(&ppu, 40, 18), color00);
+        assert_eq!(pixel(&ppu, 40, 25), color11);
+
+        // Sprites can be turned off
+        ppu.registers[LCD_CONTROL_REGISTER] &= !0b10;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 20, 10), color00);
+    }
+
+    // https://gbdev.io/pandocs/#ff4a-wy-window-y-position-r-w-ff4b-wx-window-x-position-7-r-w
+    #[test]
+    fn test_ppu_window () {
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b11110001;
+        ppu.registers[WY] = 20;
+        ppu.registers[WX] = 47;
+        // Tile 1 is color 3 on row 0, color 2 on row 4 and color 1 everywhere else
+        for row in 0..8 {
+            let (lo, hi) = match row { 0 => (0xFF, 0xFF), 4 => (0x00, 0xFF), _ => (0xFF, 0x00) };
+            ppu.vram[16 + row * 2] = lo;
+            ppu.vram[16 + row * 2 + 1] = hi;
+        }
+        for i in 0x1C00..0x2000 {
+            ppu.vram[i] = 1;
+        }
+
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        // Turning the window off for a few lines pauses its line counter
+        while ppu.registers[LY] != 24 {
+            ppu.tick();
+        }
+        ppu.registers[LCD_CONTROL_REGISTER] &= !0b100000;
+        while ppu.registers[LY] != 30 {
+            ppu.tick();
+        }
+        ppu.registers[LCD_CONTROL_REGISTER] |= 0b100000;
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+
+        assert_eq!(pixel(&ppu, 40, 19), color00);
+        assert_eq!(pixel(&ppu, 39, 20), color00);
+        assert_eq!(pixel(&ppu, 40, 20), color11);
+        assert_eq!(pixel(&ppu, 159, 20), color11);
+        assert_eq!(pixel(&ppu, 40, 21), color01);
+        for y in 24..30 {
+            assert_eq!(pixel(&ppu, 40, y), color00);
+        }
+        assert_eq!(pixel(&ppu, 40, 30), color10);
+        assert_eq!(pixel(&ppu, 40, 31), color01);
+
+        // WX below 7 cuts off the left of the window
+        ppu.registers[WX] = 3;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 0, 20), color11);
+        assert_eq!(pixel(&ppu, 0, 24), color10);
+    }
+
+    fn count_stat_interrupts(r: &Recievables) -> usize {
+        let mut count = 0;
+        while let Some(x) = r.recieve() {
+            if let SendInterrupt(Interrupt::LCDStat) = x {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // Runs from the start of one VBlank to the start of the next
+    fn stat_interrupts_in_frame(ppu: &mut PPU, r: &Recievables) -> usize {
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        count_stat_interrupts(r);
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        count_stat_interrupts(r)
+    }
+
+    // https://gbdev.io/pandocs/#ff45-lyc-ly-compare-r-w
+    #[test]
+    fn test_stat_coincidence () {
+        let mut ppu = create_test_ppu();
+        let r = Recievables::new();
+        ppu.set_recievables(r.clone());
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010001;
+        ppu.write_reg(0xFF45, 5);
+
+        while ppu.registers[LY] != 5 {
+            assert_eq!(ppu.read_reg(0xFF41) & 0b100, 0);
+            ppu.tick();
+        }
+        assert_eq!(ppu.read_reg(0xFF41) & 0b100, 0b100);
+        // Without the enable bit the flag is set, but no interrupt is requested
+        assert_eq!(count_stat_interrupts(&r), 0);
+
+        // The flag and mode bits can't be written
+        ppu.write_reg(0xFF41, 0b01000000);
+        assert_eq!(ppu.read_reg(0xFF41) & 0b111, 0b100 | ppu.registers[LCD_STATUS_REGISTER] & 0b11);
+        assert_eq!(ppu.read_reg(0xFF41) & 0b11000000, 0b11000000);
+        // Enabling the source while LY == LYC raises the line
+        assert_eq!(count_stat_interrupts(&r), 1);
+        while ppu.registers[LY] == 5 {
+            ppu.tick();
+        }
+        assert_eq!(ppu.read_reg(0xFF41) & 0b100, 0);
+        assert_eq!(count_stat_interrupts(&r), 0);
+
+        // Once per frame, not once per dot
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 1);
+
+        // Writing LYC updates the comparison straight away
+        ppu.write_reg(0xFF45, ppu.registers[LY]);
+        assert_eq!(ppu.read_reg(0xFF41) & 0b100, 0b100);
+        assert_eq!(count_stat_interrupts(&r), 1);
+    }
+
+    // https://gbdev.io/pandocs/#int-48-stat-interrupt
+    #[test]
+    fn test_stat_mode_interrupts () {
+        let mut ppu = create_test_ppu();
+        let r = Recievables::new();
+        ppu.set_recievables(r.clone());
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010001;
+        ppu.write_reg(0xFF45, 200); // never matches
+
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 0);
+
+        ppu.write_reg(0xFF41, 0b00001000); // HBlank
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 144);
+
+        ppu.write_reg(0xFF41, 0b00010000); // VBlank
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 1);
+
+        ppu.write_reg(0xFF41, 0b00100000); // OAM
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 144);
+
+        // HBlank goes straight into OAM, so the line never drops between them,
+        // only the OAM of line 0 (coming out of VBlank) requests another interrupt
+        ppu.write_reg(0xFF41, 0b00101000);
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 145);
+
+        // With VBlank as well the line is only low during mode 3
+        ppu.write_reg(0xFF41, 0b00111000);
+        assert_eq!(stat_interrupts_in_frame(&mut ppu, &r), 144);
+    }
+
+    // https://gbdev.io/pandocs/#lcdc7-lcd-display-enable
+    #[test]
+    fn test_lcd_on_off () {
+        let mut ppu = create_test_ppu();
+        ppu.registers[BGP] = 0b11100100;
+        for i in 0..16 {
+            ppu.vram[16 + i] = 0xFF;
+        }
+        for i in 0x1800..0x1C00 {
+            ppu.vram[i] = 1;
+        }
+        // The lcd starts off, and doesn't do anything
+        let ly = ppu.registers[LY];
+        for _ in 0..1000 {
+            ppu.tick();
+        }
+        assert_eq!(ppu.registers[LY], ly);
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+
+        ppu.write_reg(0xFF40, 0b10010001);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 10, 10), color11);
+
+        while ppu.registers[LY] != 50 || ppu.get_mode() != Mode::VRAM {
+            ppu.tick();
+        }
+        ppu.write_reg(0xFF40, 0b00010001);
+        assert_eq!(ppu.registers[LY], 0);
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+        assert_eq!(ppu.screen.iter().all(|px| *px == color00), true);
+        for _ in 0..1000 {
+            ppu.tick();
+        }
+        assert_eq!(ppu.registers[LY], 0);
+        assert_eq!(ppu.get_mode(), Mode::HBlank);
+
+        // The first line skips the OAM scan, and is in mode 0 instead
+        ppu.write_reg(0xFF40, 0b10010001);
+        for _ in 0..OAM_WIDTH - 1 {
+            ppu.tick();
+            assert_eq!(ppu.get_mode(), Mode::HBlank);
+        }
+        ppu.tick();
+        assert_eq!(ppu.get_mode(), Mode::VRAM);
+        assert_eq!(ppu.registers[LY], 0);
+
+        // The first frame is left blank
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        assert_eq!(ppu.screen.iter().all(|px| *px == color00), true);
+        while ppu.get_mode() == Mode::VBlank {
+            ppu.tick();
+        }
+        assert_eq!(ppu.get_mode(), Mode::OAM);
+        while ppu.get_mode() != Mode::VBlank {
+            ppu.tick();
+        }
+        assert_eq!(pixel(&ppu, 10, 10), color11);
+    }
+
+    // https://gbdev.io/pandocs/#accessing-vram-and-oam
+    #[test]
+    fn test_vram_oam_blocking () {
+        let mut ppu = create_test_ppu();
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010001;
+        ppu.write(0x8000, 0x12);
+        ppu.writeOAM(0xFE00, 0x34);
+
+        while ppu.get_mode() != Mode::OAM {
+            ppu.tick();
+        }
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert_eq!(ppu.readOAM(0xFE00), 0xFF);
+        ppu.writeOAM(0xFE00, 0x56);
+
+        while ppu.get_mode() != Mode::VRAM {
+            ppu.tick();
+        }
+        assert_eq!(ppu.read(0x8000), 0xFF);
+        assert_eq!(ppu.readOAM(0xFE00), 0xFF);
+        ppu.write(0x8000, 0x78);
+
+        while ppu.get_mode() != Mode::HBlank {
+            ppu.tick();
+        }
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert_eq!(ppu.readOAM(0xFE00), 0x34);
+
+        // Without blocking the cpu can get at both while the ppu is drawing
+        ppu.set_blocking(false);
+        while ppu.get_mode() != Mode::VRAM {
+            ppu.tick();
+        }
+        ppu.write(0x8000, 0x78);
+        ppu.writeOAM(0xFE00, 0x56);
+        assert_eq!(ppu.read(0x8000), 0x78);
+        assert_eq!(ppu.readOAM(0xFE00), 0x56);
+
+        // With the lcd off nothing is blocked
+        ppu.set_blocking(true);
+        ppu.write_reg(0xFF40, 0b00010001);
+        assert_eq!(ppu.read(0x8000), 0x78);
+        assert_eq!(ppu.readOAM(0xFE00), 0x56);
+    }
+
+    // Counts the dots of mode 3 on a line
+    fn mode3_length(ppu: &mut PPU, ly: u8) -> usize {
+        while ppu.registers[LY] != ly || ppu.get_mode() != Mode::VRAM {
+            ppu.tick();
+        }
+        let mut dots = 0;
+        while ppu.get_mode() == Mode::VRAM {
+            ppu.tick();
+            dots += 1;
+        }
+        dots
+    }
+
+    // https://gbdev.io/pandocs/#mode-3-length
+    #[test]
+    fn test_mode3_length () {
+        let mut ppu = create_test_ppu();
+        ppu.registers[LCD_CONTROL_REGISTER] = 0b10010011;
+        assert_eq!(mode3_length(&mut ppu, 10), 172);
+
+        // Pixels dropped for the fine scroll
+        ppu.registers[SCX] = 3;
+        assert_eq!(mode3_length(&mut ppu, 11), 175);
+        ppu.registers[SCX] = 8;
+        assert_eq!(mode3_length(&mut ppu, 12), 172);
+        ppu.registers[SCX] = 0;
+
+        // Restarting the fetcher for the window
+        ppu.registers[LCD_CONTROL_REGISTER] |= 0b100000;
+        ppu.registers[WX] = 87;
+        ppu.registers[WY] = 0;
+        assert_eq!(mode3_length(&mut ppu, 13), 178);
+        ppu.registers[LCD_CONTROL_REGISTER] &= !0b100000;
+
+        // A sprite at the start of a tile waits for the fetcher to finish it
+        set_sprite(&mut ppu, 0, 40, 14, 0, 0);
+        assert_eq!(mode3_length(&mut ppu, 14), 172 + 11);
+        // Near the end of a tile it doesn't have to wait
+        set_sprite(&mut ppu, 0, 45, 15, 0, 0);
+        assert_eq!(mode3_length(&mut ppu, 15), 172 + 6);
+        // Only the first sprite in a tile waits
+        ppu.oam_ram = [Sprite::new(); 40].to_vec();
+        set_sprite(&mut ppu, 0, 40, 16, 0, 0);
+        set_sprite(&mut ppu, 1, 42, 16, 0, 0);
+        assert_eq!(mode3_length(&mut ppu, 16), 172 + 11 + 6);
+        // The scroll moves where the tiles are
+        ppu.oam_ram = [Sprite::new(); 40].to_vec();
+        ppu.registers[SCX] = 3;
+        set_sprite(&mut ppu, 0, 40, 17, 0, 0);
+        set_sprite(&mut ppu, 1, 45, 17, 0, 0);
+        assert_eq!(mode3_length(&mut ppu, 17), 172 + 3 + 8 + 11);
+        ppu.registers[SCX] = 0;
+        // A sprite hidden off the left edge still costs the most
+        ppu.oam_ram = [Sprite::new(); 40].to_vec();
+        ppu.oam_ram[0] = Sprite { pos_x: 0, pos_y: 18 + 16, tile: 0, flags: 0 };
+        assert_eq!(mode3_length(&mut ppu, 18), 172 + 11);
+        // but one off the right edge isn't fetched
+        ppu.oam_ram[0] = Sprite { pos_x: 168, pos_y: 19 + 16, tile: 0, flags: 0 };
+        assert_eq!(mode3_length(&mut ppu, 19), 172);
+
+        // Nothing is fetched with sprites off
+        ppu.registers[LCD_CONTROL_REGISTER] &= !0b10;
+        ppu.oam_ram = [Sprite::new(); 40].to_vec();
+        set_sprite(&mut ppu, 0, 40, 20, 0, 0);
+        assert_eq!(mode3_length(&mut ppu, 20), 172);
+    }
+}
@@ -0,0 +1,358 @@
+use crate::serial::SerialLink;
+
+// https://gbdev.io/pandocs/#gameboy-printer
+// The printer sits on the other end of the link cable, and the game sends it packets:
+// 0x88 0x33, command, compression, length (2 bytes little endian), data,
+// checksum (2 bytes little endian), then two 0x00 bytes. While those last two are
+// sent the printer answers with 0x81 and then its status. Everything else gets 0x00
+const MAGIC1: u8 = 0x88;
+const MAGIC2: u8 = 0x33;
+const ALIVE: u8 = 0x81;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0b1;
+const PRINTING: u8 = 0b10;
+const IMAGE_FULL: u8 = 0b100;
+const UNPROCESSED: u8 = 0b1000;
+
+// The paper is 20 tiles wide, and the printer holds up to 9 bands of 2 rows of tiles
+pub const PRINT_WIDTH: usize = 160;
+const TILE_ROW_SIZE: usize = PRINT_WIDTH / 8 * 16;
+const BUFFER_SIZE: usize = TILE_ROW_SIZE * 2 * 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+// A printed strip of paper, PRINT_WIDTH wide. Each pixel is a shade from 0 (white) to 3 (black)
+pub struct PrintedImage {
+    pub shades: Vec<u8>,
+}
+
+impl PrintedImage {
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.shades.len() * 4);
+        for shade in self.shades.iter() {
+            let v = 0xFF - 0x55 * (shade & 0b11);
+            rgba.extend_from_slice(&[v, v, v, 0xFF]);
+        }
+        rgba
+    }
+}
+
+pub struct Printer {
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    sum: u16, // checksum of what's been recieved so far
+    checksum: u16, // checksum sent with the packet
+    status: u8,
+    image: Vec<u8>, // tile data waiting to be printed
+    on_print: Box<dyn FnMut(PrintedImage)>,
+}
+
+impl Printer {
+    pub fn new(on_print: Box<dyn FnMut(PrintedImage)>) -> Self {
+        Self {
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            status: 0,
+            image: Vec::new(),
+            on_print,
+        }
+    }
+
+    fn add_to_sum(&mut self, byte: u8) {
+        self.sum = self.sum.wrapping_add(byte as u16);
+    }
+
+    // https://gbdev.io/pandocs/#compression
+    // A control byte with bit 7 set repeats the next byte (control & 0x7F) + 2 times,
+    // otherwise the next control + 1 bytes are copied as is
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let control = data[i];
+            i += 1;
+            if control & 0x80 > 0 {
+                let count = (control & 0x7F) as usize + 2;
+                if let Some(&byte) = data.get(i) {
+                    out.resize(out.len() + count, byte);
+                }
+                i += 1;
+            } else {
+                let count = control as usize + 1;
+                let end = (i + count).min(data.len());
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+        }
+        out
+    }
+
+    // Runs the packet once it's all been recieved, just before the status goes out
+    fn run_command(&mut self) {
+        // The print from the last status check is done
+        self.status &= !PRINTING;
+        if self.sum != self.checksum {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+        match self.command {
+            INIT => {
+                self.image.clear();
+                self.status = 0;
+            }
+            DATA => {
+                let data = if self.compressed { Self::decompress(&self.data) } else { self.data.clone() };
+                let space = BUFFER_SIZE - self.image.len();
+                self.image.extend_from_slice(&data[..data.len().min(space)]);
+                if !self.image.is_empty() {
+                    self.status |= UNPROCESSED;
+                }
+                if self.image.len() == BUFFER_SIZE {
+                    self.status |= IMAGE_FULL;
+                }
+            }
+            PRINT => {
+                // Sheets, margins, palette, exposure
+                let palette = match self.data.get(2) {
+                    Some(0) | None => 0xE4, // 0 is treated as the default palette
+                    Some(&p) => p,
+                };
+                self.print(palette);
+                self.status &= !(UNPROCESSED | IMAGE_FULL);
+                self.status |= PRINTING;
+            }
+            STATUS => (), // only wants the status
+            _ => (),
+        }
+    }
+
+    fn print(&mut self, palette: u8) {
+        // A partly sent row of tiles is printed with the rest left blank
+        let rows = self.image.len().div_ceil(TILE_ROW_SIZE);
+        if rows == 0 {
+            return;
+        }
+        let height = rows * 8;
+        let mut shades = vec![0u8; PRINT_WIDTH * height];
+        for (t, tile) in self.image.chunks_exact(16).enumerate() {
+            let tx = t % (PRINT_WIDTH / 8) * 8;
+            let ty = t / (PRINT_WIDTH / 8) * 8;
+            for y in 0..8 {
+                let lo = tile[y * 2];
+                let hi = tile[y * 2 + 1];
+                for x in 0..8 {
+                    let bit = 7 - x;
+                    let value = (lo >> bit & 1) | (hi >> bit & 1) << 1;
+                    shades[(ty + y) * PRINT_WIDTH + tx + x] = palette >> (value * 2) & 0b11;
+                }
+            }
+        }
+        self.image.clear();
+        (self.on_print)(PrintedImage { shades });
+    }
+}
+
+impl SerialLink for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic1 if byte == MAGIC1 => State::Magic2,
+            State::Magic1 => State::Magic1,
+            State::Magic2 if byte == MAGIC2 => State::Command,
+            State::Magic2 => State::Magic1,
+            State::Command => {
+                self.command = byte;
+                self.sum = 0;
+                self.data.clear();
+                self.add_to_sum(byte);
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 1 > 0;
+                self.add_to_sum(byte);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as usize;
+                self.add_to_sum(byte);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as usize) << 8;
+                self.add_to_sum(byte);
+                if self.length == 0 { State::ChecksumLow } else { State::Data }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.add_to_sum(byte);
+                if self.data.len() == self.length { State::ChecksumLow } else { State::Data }
+            }
+            State::ChecksumLow => {
+                self.checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                State::Alive
+            }
+            State::Alive => {
+                reply = ALIVE;
+                self.run_command();
+                State::Status
+            }
+            State::Status => {
+                reply = self.status;
+                State::Magic1
+            }
+        };
+        reply
+    }
+
+    // The game always drives the clock
+    fn poll(&mut self, _waiting: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn create_test_printer() -> (Printer, Rc<RefCell<Vec<PrintedImage>>>) {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let p = printed.clone();
+        (Printer::new(Box::new(move |img| p.borrow_mut().push(img))), printed)
+    }
+
+    // Sends a whole packet, and returns the alive and status bytes
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8], checksum_offset: u16) -> (u8, u8) {
+        let mut packet = vec![MAGIC1, MAGIC2, command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+        packet.extend_from_slice(data);
+        let sum = packet[2..].iter().fold(0u16, |s, b| s.wrapping_add(*b as u16)).wrapping_add(checksum_offset);
+        packet.extend_from_slice(&[sum as u8, (sum >> 8) as u8]);
+        for b in packet.iter() {
+            assert_eq!(printer.transfer(*b), 0x00);
+        }
+        (printer.transfer(0), printer.transfer(0))
+    }
+
+    // One band of 2 rows of tiles. Tile n has color n % 4 on every pixel
+    fn test_band() -> Vec<u8> {
+        let mut band = Vec::new();
+        for t in 0..40 {
+            let (lo, hi) = match t % 4 { 0 => (0x00, 0x00), 1 => (0xFF, 0x00), 2 => (0x00, 0xFF), _ => (0xFF, 0xFF) };
+            for _ in 0..8 {
+                band.push(lo);
+                band.push(hi);
+            }
+        }
+        band
+    }
+
+    #[test]
+    fn test_printer() {
+        let (mut printer, printed) = create_test_printer();
+        assert_eq!(send_packet(&mut printer, INIT, false, &[], 0), (ALIVE, 0));
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[], 0), (ALIVE, 0));
+
+        assert_eq!(send_packet(&mut printer, DATA, false, &test_band(), 0), (ALIVE, UNPROCESSED));
+        assert_eq!(send_packet(&mut printer, DATA, false, &[], 0), (ALIVE, UNPROCESSED));
+        // Palette 0b00011011 reverses the colors
+        assert_eq!(send_packet(&mut printer, PRINT, false, &[1, 0x13, 0b00011011, 0x40], 0), (ALIVE, PRINTING));
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[], 0), (ALIVE, 0));
+
+        let printed = printed.borrow();
+        assert_eq!(printed.len(), 1);
+        let img = &printed[0];
+        assert_eq!(img.shades.len(), PRINT_WIDTH * 16);
+        assert_eq!(img.shades[0], 3);
+        assert_eq!(img.shades[8], 2);
+        assert_eq!(img.shades[16], 1);
+        assert_eq!(img.shades[24], 0);
+        assert_eq!(img.shades[8 * PRINT_WIDTH], 3);
+        assert_eq!(img.to_rgba()[..8].to_vec(), vec![0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn test_printer_compression() {
+        assert_eq!(Printer::decompress(&[0x82, 0xAA, 0x01, 0x12, 0x34]), vec![0xAA, 0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+
+        // A band where every byte is 0xFF compresses to runs of 129
+        let (mut printer, printed) = create_test_printer();
+        send_packet(&mut printer, INIT, false, &[], 0);
+        let mut data = Vec::new();
+        for _ in 0..(TILE_ROW_SIZE * 2 / 128) {
+            data.extend_from_slice(&[0xFE, 0xFF]);
+        }
+        assert_eq!(send_packet(&mut printer, DATA, true, &data, 0), (ALIVE, UNPROCESSED));
+        send_packet(&mut printer, PRINT, false, &[1, 0, 0, 0], 0);
+        let printed = printed.borrow();
+        assert_eq!(printed[0].shades.len(), PRINT_WIDTH * 16);
+        assert_eq!(printed[0].shades.iter().all(|s| *s == 3), true);
+    }
+
+    #[test]
+    fn test_printer_partial_row() {
+        let (mut printer, printed) = create_test_printer();
+        send_packet(&mut printer, INIT, false, &[], 0);
+        // Nothing to print means no paper comes out
+        assert_eq!(send_packet(&mut printer, PRINT, false, &[1, 0, 0, 0], 0), (ALIVE, PRINTING));
+        assert_eq!(printed.borrow().len(), 0);
+
+        // 22 tiles is one full row, and two tiles of the next
+        let mut data = test_band();
+        data.truncate(22 * 16);
+        send_packet(&mut printer, DATA, false, &data, 0);
+        send_packet(&mut printer, PRINT, false, &[1, 0, 0, 0], 0);
+        let printed = printed.borrow();
+        assert_eq!(printed[0].shades.len(), PRINT_WIDTH * 16);
+        assert_eq!(printed[0].shades[8 * PRINT_WIDTH + 8], 1);
+        assert_eq!(printed[0].shades[8 * PRINT_WIDTH + 16], 0);
+    }
+
+    #[test]
+    fn test_printer_checksum() {
+        let (mut printer, printed) = create_test_printer();
+        send_packet(&mut printer, INIT, false, &[], 0);
+        assert_eq!(send_packet(&mut printer, DATA, false, &test_band(), 1), (ALIVE, CHECKSUM_ERROR));
+        assert_eq!(send_packet(&mut printer, PRINT, false, &[1, 0, 0, 0], 1), (ALIVE, CHECKSUM_ERROR));
+        assert_eq!(printed.borrow().len(), 0);
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[], 0), (ALIVE, 0));
+
+        // Bytes outside of a packet are ignored
+        for b in [0x00, 0x12, MAGIC1, 0x00].iter() {
+            assert_eq!(printer.transfer(*b), 0x00);
+        }
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[], 0), (ALIVE, 0));
+    }
+}
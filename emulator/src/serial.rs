@@ -0,0 +1,387 @@
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use crate::cpu_recievable::{Recievables, CpuRecievable::*, Interrupt};
+
+// https://gbdev.io/pandocs/#serial-data-transfer-link-cable
+// FF01 SB - Serial transfer data
+// FF02 SC - Serial Transfer Control
+// Bit 7 - Transfer Start Flag (0=No transfer is in progress or requested, 1=Transfer in progress, or requested)
+// Bit 1 - Clock Speed (0=Normal, 1=Fast) ** CGB Mode Only **
+// Bit 0 - Shift Clock (0=External Clock, 1=Internal Clock)
+const SB: u16 = 0xFF01;
+const SC: u16 = 0xFF02;
+
+// The internal clock shifts a bit every 128 cpu cycles (8192Hz), or every 4
+// with the CGB fast clock. Double speed doubles both, as they count cpu cycles
+const BIT_CYCLES: usize = 128;
+const FAST_BIT_CYCLES: usize = 4;
+
+// Whatever is on the other end of the link cable
+pub trait SerialLink {
+    // This side is driving the clock, and has shifted out all 8 bits of byte.
+    // Returns the byte that was shifted in from the other side
+    fn transfer(&mut self, byte: u8) -> u8;
+    // Called every cycle, before any transfer. waiting has the byte in SB while
+    // this side has a transfer waiting on the other side's clock, and None
+    // otherwise. Returns the byte shifted in once the other side has done a transfer
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8>;
+}
+
+// Nothing is plugged in, so the input line floats high
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+    fn poll(&mut self, _waiting: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+pub fn disconnected() -> Box<dyn SerialLink> {
+    Box::new(Disconnected)
+}
+
+// The output is wired straight back into the input
+#[cfg(test)]
+pub struct Loopback;
+
+#[cfg(test)]
+impl SerialLink for Loopback {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        byte
+    }
+    fn poll(&mut self, _waiting: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+// Writes out every byte the game sends, otherwise it acts like Disconnected.
+// blargg's test roms print their results this way
+pub struct Capture<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Capture<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> SerialLink for Capture<W> {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.out.write_all(&[byte]).ok();
+        self.out.flush().ok();
+        0xFF
+    }
+    fn poll(&mut self, _waiting: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Default)]
+struct PairState {
+    waiting: [Option<u8>; 2], // what each side has in SB while it waits on the other's clock
+    inbox: [Option<u8>; 2], // bytes shifted into a waiting side
+}
+
+// One end of a cable between two gameboys running in the same thread
+pub struct LinkEnd {
+    side: usize,
+    state: Rc<RefCell<PairState>>,
+}
+
+pub fn link_pair() -> (LinkEnd, LinkEnd) {
+    let state = Rc::new(RefCell::new(PairState::default()));
+    (LinkEnd { side: 0, state: state.clone() }, LinkEnd { side: 1, state })
+}
+
+impl SerialLink for LinkEnd {
+    // If the other side isn't waiting with a transfer it doesn't shift, and we read 0xFF
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let mut state = self.state.borrow_mut();
+        let other = 1 - self.side;
+        match state.waiting[other].take() {
+            Some(theirs) => {
+                state.inbox[other] = Some(byte);
+                theirs
+            }
+            None => 0xFF,
+        }
+    }
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        if let Some(byte) = state.inbox[self.side].take() {
+            return Some(byte);
+        }
+        state.waiting[self.side] = waiting;
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Serial {
+    data: u8, // SB
+    control: u8, // SC
+    cgb: bool, // the fast clock is only available in CGB mode
+    ticker: usize, // cycles into the current bit
+    bits_left: u8, // bits left to shift with the internal clock, 0 when not transferring
+    #[serde(skip, default="crate::serial::disconnected")]
+    link: Box<dyn SerialLink>,
+    #[serde(skip, default="crate::cpu_recievable::none_recivables")]
+    recievables: Option<Recievables>,
+}
+
+// The link is left behind, see Bus::take_serial_link to move it over
+impl Clone for Serial {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data,
+            control: self.control,
+            cgb: self.cgb,
+            ticker: self.ticker,
+            bits_left: self.bits_left,
+            link: disconnected(),
+            recievables: None,
+        }
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            data: 0,
+            control: 0,
+            cgb: false,
+            ticker: 0,
+            bits_left: 0,
+            link: disconnected(),
+            recievables: None,
+        }
+    }
+
+    pub fn set_recievables(&mut self, recievables: Recievables) {
+        self.recievables = Some(recievables)
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn SerialLink>) {
+        self.link = link;
+    }
+
+    pub fn take_link(&mut self) -> Box<dyn SerialLink> {
+        std::mem::replace(&mut self.link, disconnected())
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        match loc {
+            SB => self.data,
+            SC if self.cgb => 0b01111100 | self.control,
+            SC => 0b01111110 | self.control,
+            _ => panic!("Address should not have been routed to serial"),
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) {
+        match loc {
+            SB => self.data = val,
+            SC => {
+                self.control = val & if self.cgb { 0b10000011 } else { 0b10000001 };
+                self.ticker = 0;
+                self.bits_left = if self.is_transferring() && self.is_internal_clock() { 8 } else { 0 };
+            }
+            _ => panic!("Address should not have been routed to serial"),
+        }
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.control & 0b10000000 > 0
+    }
+
+    fn is_internal_clock(&self) -> bool {
+        self.control & 0b1 > 0
+    }
+
+    fn bit_cycles(&self) -> usize {
+        if self.control & 0b10 > 0 { FAST_BIT_CYCLES } else { BIT_CYCLES }
+    }
+
+    // SB is swapped with the other side in one go once all 8 bits are shifted
+    fn finish(&mut self, byte: u8) {
+        self.data = byte;
+        self.control &= 0b01111111;
+        self.bits_left = 0;
+        match &self.recievables {
+            Some(r) => r.send(SendInterrupt(Interrupt::Serial)),
+            None => ()
+        }
+    }
+
+    pub fn tick(&mut self) {
+        let waiting = if self.is_transferring() && !self.is_internal_clock() { Some(self.data) } else { None };
+        if let Some(byte) = self.link.poll(waiting) {
+            self.finish(byte);
+        }
+        if self.bits_left > 0 {
+            self.ticker += 1;
+            if self.ticker == self.bit_cycles() {
+                self.ticker = 0;
+                self.bits_left -= 1;
+                if self.bits_left == 0 {
+                    let byte = self.link.transfer(self.data);
+                    self.finish(byte);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count_serial_interrupts(r: &Recievables) -> usize {
+        let mut count = 0;
+        while let Some(x) = r.recieve() {
+            if let SendInterrupt(Interrupt::Serial) = x {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn create_test_serial(link: Box<dyn SerialLink>) -> (Serial, Recievables) {
+        let mut serial = Serial::new();
+        let r = Recievables::new();
+        serial.set_recievables(r.clone());
+        serial.set_link(link);
+        (serial, r)
+    }
+
+    #[test]
+    fn test_serial_internal_clock() {
+        let (mut serial, r) = create_test_serial(disconnected());
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x81);
+        assert_eq!(serial.read(SC), 0xFF);
+        for _ in 0..(8 * BIT_CYCLES - 1) {
+            serial.tick();
+        }
+        assert_eq!(serial.read(SC) & 0b10000000, 0b10000000);
+        assert_eq!(serial.read(SB), 0x42);
+        assert_eq!(count_serial_interrupts(&r), 0);
+
+        // Nothing is connected, so we read all 1s
+        serial.tick();
+        assert_eq!(serial.read(SC), 0x7F);
+        assert_eq!(serial.read(SB), 0xFF);
+        assert_eq!(count_serial_interrupts(&r), 1);
+
+        // The fast clock only exists in CGB mode
+        serial.write(SC, 0x83);
+        assert_eq!(serial.read(SC), 0xFF);
+        for _ in 0..(8 * FAST_BIT_CYCLES) {
+            serial.tick();
+        }
+        assert_eq!(count_serial_interrupts(&r), 0);
+        serial.set_cgb_mode(true);
+        serial.write(SC, 0x83);
+        for _ in 0..(8 * FAST_BIT_CYCLES) {
+            serial.tick();
+        }
+        assert_eq!(serial.read(SC), 0x7F);
+        assert_eq!(count_serial_interrupts(&r), 1);
+    }
+
+    #[test]
+    fn test_serial_external_clock() {
+        // Without anything to drive the clock the transfer never finishes
+        let (mut serial, r) = create_test_serial(disconnected());
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x80);
+        for _ in 0..(16 * BIT_CYCLES) {
+            serial.tick();
+        }
+        assert_eq!(serial.read(SC) & 0b10000000, 0b10000000);
+        assert_eq!(serial.read(SB), 0x42);
+        assert_eq!(count_serial_interrupts(&r), 0);
+    }
+
+    #[test]
+    fn test_serial_loopback() {
+        let (mut serial, r) = create_test_serial(Box::new(Loopback));
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x81);
+        for _ in 0..(8 * BIT_CYCLES) {
+            serial.tick();
+        }
+        assert_eq!(serial.read(SB), 0x42);
+        assert_eq!(count_serial_interrupts(&r), 1);
+    }
+
+    #[test]
+    fn test_serial_capture() {
+        let mut capture = Capture::new(Vec::new());
+        assert_eq!(capture.transfer(b'o'), 0xFF);
+        assert_eq!(capture.transfer(b'k'), 0xFF);
+        assert_eq!(capture.poll(Some(0)), None);
+        assert_eq!(capture.into_inner(), b"ok".to_vec());
+    }
+
+    #[test]
+    fn test_serial_link_pair() {
+        let (a, b) = link_pair();
+        let (mut master, rm) = create_test_serial(Box::new(a));
+        let (mut slave, rs) = create_test_serial(Box::new(b));
+
+        // The slave isn't ready, so the master reads 0xFF
+        master.write(SB, 0x11);
+        master.write(SC, 0x81);
+        for _ in 0..(8 * BIT_CYCLES) {
+            master.tick();
+            slave.tick();
+        }
+        assert_eq!(master.read(SB), 0xFF);
+        assert_eq!(count_serial_interrupts(&rm), 1);
+        assert_eq!(count_serial_interrupts(&rs), 0);
+
+        slave.write(SB, 0x22);
+        slave.write(SC, 0x80);
+        master.write(SB, 0x33);
+        master.write(SC, 0x81);
+        for _ in 0..(8 * BIT_CYCLES) {
+            master.tick();
+            slave.tick();
+        }
+        assert_eq!(master.read(SB), 0x22);
+        assert_eq!(count_serial_interrupts(&rm), 1);
+        // The slave gets its byte on its next cycle
+        slave.tick();
+        assert_eq!(slave.read(SB), 0x33);
+        assert_eq!(slave.read(SC) & 0b10000000, 0);
+        assert_eq!(count_serial_interrupts(&rs), 1);
+
+        // A slave that gives up on the transfer isn't shifted
+        slave.write(SB, 0x44);
+        slave.write(SC, 0x80);
+        slave.tick();
+        slave.write(SC, 0x00);
+        slave.tick();
+        master.write(SC, 0x81);
+        for _ in 0..(8 * BIT_CYCLES) {
+            master.tick();
+            slave.tick();
+        }
+        assert_eq!(master.read(SB), 0xFF);
+        assert_eq!(slave.read(SB), 0x44);
+    }
+}
@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use crate::ppu::{Screen, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// https://gbdev.io/pandocs/#sgb-functions
+// The SGB draws the gameboy screen in the middle of a 256x224 SNES frame,
+// surrounded by a border the game can upload
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+const SCREEN_X: usize = (SGB_WIDTH - SCREEN_WIDTH) / 2;
+const SCREEN_Y: usize = (SGB_HEIGHT - SCREEN_HEIGHT) / 2;
+
+// Palette attributes are per 8x8 cell of the gameboy screen
+const ATTR_WIDTH: usize = SCREEN_WIDTH / 8;
+const ATTR_HEIGHT: usize = SCREEN_HEIGHT / 8;
+
+const PACKET_SIZE: usize = 16;
+const BORDER_TILES_SIZE: usize = 0x2000; // 256 4bpp tiles
+const BORDER_MAP_SIZE: usize = 0x800; // 32x28 entries of 2 bytes, padded to 32x32
+
+// https://gbdev.io/pandocs/#sgb-command-summary
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+// MASK_EN modes
+const MASK_CANCEL: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOR0: u8 = 3;
+
+// Palette 1-A, what the SGB shows before a game picks its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sgb {
+    // Packets are sent a bit at a time by pulling P14 (a 0) or P15 (a 1) low,
+    // with both high between bits. Both low resets and starts a new packet
+    receiving: bool,
+    bit: usize,
+    packet: [u8; PACKET_SIZE],
+    data: Vec<u8>, // all the packets of the current command
+    last_write: u8,
+
+    players: u8,
+    player: u8,
+
+    palettes: [u16; 16], // 4 palettes of 4 colors, color 0 is shared
+    attributes: Vec<u8>, // size of 20x18, the palette of each cell
+    mask: u8,
+    frozen: Option<Screen>,
+
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: Vec<u16>, // palettes 4-7, 16 colors each
+}
+
+fn color(data: &[u8], i: usize) -> u16 {
+    (data[i] as u16 | (data[i + 1] as u16) << 8) & 0x7FFF
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        let mut palettes = [0u16; 16];
+        for p in 0..4 {
+            palettes[p * 4..p * 4 + 4].copy_from_slice(&DEFAULT_PALETTE);
+        }
+        Self {
+            receiving: false,
+            bit: 0,
+            packet: [0; PACKET_SIZE],
+            data: Vec::new(),
+            last_write: 0x30,
+            players: 1,
+            player: 0,
+            palettes,
+            attributes: [0u8; ATTR_WIDTH * ATTR_HEIGHT].to_vec(),
+            mask: MASK_CANCEL,
+            frozen: None,
+            border_tiles: [0u8; BORDER_TILES_SIZE].to_vec(),
+            border_map: [0u8; BORDER_MAP_SIZE].to_vec(),
+            border_palettes: [0u16; 64].to_vec(),
+        }
+    }
+
+    // Called on every write to 0xFF00, with the ppu to copy VRAM transfers from
+    pub fn write_joypad(&mut self, val: u8, ppu: &crate::ppu::PPU) {
+        let val = val & 0b110000;
+        match val {
+            0b000000 => {
+                self.receiving = true;
+                self.bit = 0;
+                self.packet = [0; PACKET_SIZE];
+            }
+            0b010000 | 0b100000 if self.receiving && self.last_write == 0b110000 => {
+                if val == 0b010000 {
+                    self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                }
+                self.bit += 1;
+                if self.bit == PACKET_SIZE * 8 {
+                    // The stop bit that follows is ignored
+                    self.receiving = false;
+                    self.receive_packet(ppu);
+                }
+            }
+            // Releasing P15 moves on to the next controller
+            0b110000 if !self.receiving && self.last_write & 0b100000 == 0 && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            _ => (),
+        }
+        self.last_write = val;
+    }
+
+    // With MLT_REQ on, deselecting both lines reads the controller number
+    // (0xF for player 1, 0xE for player 2 ...). Only player 1 has buttons
+    pub fn read_joypad(&self, pins: u8) -> u8 {
+        if self.players > 1 && pins & 0b110000 == 0b110000 {
+            (pins & 0b110000) | (0xF - self.player)
+        } else if self.player != 0 {
+            pins | 0xF
+        } else {
+            pins
+        }
+    }
+
+    fn receive_packet(&mut self, ppu: &crate::ppu::PPU) {
+        self.data.extend_from_slice(&self.packet);
+        let length = (self.data[0] & 0b111).max(1) as usize;
+        if self.data.len() >= length * PACKET_SIZE {
+            let data = std::mem::take(&mut self.data);
+            self.run_command(&data, ppu);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8], ppu: &crate::ppu::PPU) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palettes(data, 0, 1),
+            PAL23 => self.set_palettes(data, 2, 3),
+            PAL03 => self.set_palettes(data, 0, 3),
+            PAL12 => self.set_palettes(data, 1, 2),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    0b01 => 2,
+                    0b11 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => {
+                let offset = if data[1] & 0b1 > 0 { BORDER_TILES_SIZE / 2 } else { 0 };
+                let vram = ppu.sgb_transfer_data();
+                self.border_tiles[offset..offset + BORDER_TILES_SIZE / 2].copy_from_slice(&vram);
+            }
+            PCT_TRN => {
+                let vram = ppu.sgb_transfer_data();
+                self.border_map.copy_from_slice(&vram[..BORDER_MAP_SIZE]);
+                for i in 0..self.border_palettes.len() {
+                    self.border_palettes[i] = color(&vram, BORDER_MAP_SIZE + i * 2);
+                }
+            }
+            MASK_EN => {
+                self.mask = data[1] & 0b11;
+                self.frozen = match self.mask {
+                    MASK_FREEZE => Some(ppu.get_screen().clone()),
+                    _ => None,
+                };
+            }
+            _ => (), // unsupported commands are ignored
+        }
+    }
+
+    // Color 0 is shared by all 4 palettes, so setting it for one sets it for all
+    fn set_palettes(&mut self, data: &[u8], a: usize, b: usize) {
+        let color0 = color(data, 1);
+        for p in 0..4 {
+            self.palettes[p * 4] = color0;
+        }
+        for i in 1..4 {
+            self.palettes[a * 4 + i] = color(data, 1 + i * 2);
+            self.palettes[b * 4 + i] = color(data, 7 + i * 2);
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        if x < ATTR_WIDTH && y < ATTR_HEIGHT {
+            self.attributes[y * ATTR_WIDTH + x] = palette & 0b11;
+        }
+    }
+
+    // Each data set is 6 bytes: control, palettes, x1, y1, x2, y2
+    // Control bit 0 changes the inside, bit 1 the border, bit 2 the outside
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] & 0b11111) as usize;
+        for set in data[2..].chunks_exact(6).take(sets) {
+            let mut control = set[0] & 0b111;
+            // Changing only the inside or outside also changes the border
+            let border_palette = match control {
+                0b001 => { control |= 0b010; set[1] & 0b11 }
+                0b100 => { control |= 0b010; set[1] >> 4 & 0b11 }
+                _ => set[1] >> 2 & 0b11,
+            };
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let inside_box = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = inside_box && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_border {
+                        if control & 0b010 > 0 {
+                            self.set_attribute(x, y, border_palette);
+                        }
+                    } else if inside_box {
+                        if control & 0b001 > 0 {
+                            self.set_attribute(x, y, set[1] & 0b11);
+                        }
+                    } else if control & 0b100 > 0 {
+                        self.set_attribute(x, y, set[1] >> 4 & 0b11);
+                    }
+                }
+            }
+        }
+    }
+
+    // Each byte is a line: bits 0-4 line number, bits 5-6 palette, bit 7 set for a row
+    fn attr_lin(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(data.len() - 2);
+        for &line in data[2..2 + sets].iter() {
+            let n = (line & 0b11111) as usize;
+            let palette = line >> 5 & 0b11;
+            if line & 0b10000000 > 0 {
+                for x in 0..ATTR_WIDTH {
+                    self.set_attribute(x, n, palette);
+                }
+            } else {
+                for y in 0..ATTR_HEIGHT {
+                    self.set_attribute(n, y, palette);
+                }
+            }
+        }
+    }
+
+    // Splits the screen at a row or column: bits 0-1 palette right/below,
+    // bits 2-3 palette left/above, bits 4-5 palette on the line, bit 6 set to split rows
+    fn attr_div(&mut self, data: &[u8]) {
+        let split = data[2] as usize;
+        for y in 0..ATTR_HEIGHT {
+            for x in 0..ATTR_WIDTH {
+                let pos = if data[1] & 0b1000000 > 0 { y } else { x };
+                let palette = match pos {
+                    p if p < split => data[1] >> 2,
+                    p if p == split => data[1] >> 4,
+                    _ => data[1],
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    // Sets cells one after another from a starting point, 2 bits per cell
+    // with the first cell in the top bits. Byte 5 is 0 to go across, 1 to go down
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = (data[3] as usize | (data[4] as usize) << 8).min((data.len() - 6) * 4);
+        let down = data[5] & 0b1 > 0;
+        for i in 0..count {
+            let palette = data[6 + i / 4] >> (6 - (i % 4) * 2);
+            self.set_attribute(x, y, palette);
+            if down {
+                y += 1;
+                if y >= ATTR_HEIGHT {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x >= ATTR_WIDTH {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // Border tiles are in the SNES 4bpp format, each row has planes 0 and 1
+    // interleaved in the first 16 bytes and planes 2 and 3 in the second 16
+    fn border_pixel(&self, tile: usize, x: usize, y: usize) -> usize {
+        let loc = tile * 32 + y * 2;
+        let t = &self.border_tiles;
+        let bit = |b: u8| ((b >> (7 - x)) & 1) as usize;
+        bit(t[loc]) | bit(t[loc + 1]) << 1 | bit(t[loc + 16]) << 2 | bit(t[loc + 17]) << 3
+    }
+
+    pub fn get_canvas(&self, screen: &Screen) -> Vec<u32> {
+        use crate::ppu::color15_to_abgr;
+        let mut canvas = vec![color15_to_abgr(self.palettes[0]); SGB_WIDTH * SGB_HEIGHT];
+
+        // Border map entries: bits 0-7 tile, bits 10-12 palette (4-7), bit 14 x flip, bit 15 y flip
+        for ty in 0..(SGB_HEIGHT / 8) {
+            for tx in 0..(SGB_WIDTH / 8) {
+                let loc = (ty * 32 + tx) * 2;
+                let entry = self.border_map[loc] as usize | (self.border_map[loc + 1] as usize) << 8;
+                let palette = (entry >> 10) & 0b11;
+                for py in 0..8 {
+                    for px in 0..8 {
+                        let x = if entry & 0x4000 > 0 { 7 - px } else { px };
+                        let y = if entry & 0x8000 > 0 { 7 - py } else { py };
+                        let c = self.border_pixel(entry & 0xFF, x, y);
+                        if c != 0 {
+                            let color = self.border_palettes[palette * 16 + c];
+                            canvas[(ty * 8 + py) * SGB_WIDTH + tx * 8 + px] = color15_to_abgr(color);
+                        }
+                    }
+                }
+            }
+        }
+
+        let screen = self.frozen.as_ref().unwrap_or(screen);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    MASK_BLACK => 0,
+                    MASK_COLOR0 => self.palettes[0],
+                    _ => {
+                        let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+                        self.palettes[palette * 4 + (screen[y * SCREEN_WIDTH + x] & 0b11) as usize]
+                    }
+                };
+                canvas[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X] = color15_to_abgr(color);
+            }
+        }
+        canvas
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ppu::PPU;
+
+    fn send_packets(sgb: &mut Sgb, ppu: &PPU, data: &[u8]) {
+        for packet in data.chunks(PACKET_SIZE) {
+            sgb.write_joypad(0x00, ppu);
+            sgb.write_joypad(0x30, ppu);
+            for i in 0..(PACKET_SIZE * 8) {
+                let byte = packet.get(i / 8).copied().unwrap_or(0);
+                sgb.write_joypad(if byte >> (i % 8) & 1 > 0 { 0x10 } else { 0x20 }, ppu);
+                sgb.write_joypad(0x30, ppu);
+            }
+            // stop bit
+            sgb.write_joypad(0x20, ppu);
+            sgb.write_joypad(0x30, ppu);
+        }
+    }
+
+    #[test]
+    fn test_sgb_palettes () {
+        let ppu = PPU::new();
+        let mut sgb = Sgb::new();
+        let mut packet = vec![PAL12 << 3 | 1];
+        for c in [0x1234u16, 0x11, 0x12, 0x13, 0x21, 0x22, 0x23].iter() {
+            packet.push((c & 0xFF) as u8);
+            packet.push((c >> 8) as u8);
+        }
+        send_packets(&mut sgb, &ppu, &packet);
+        assert_eq!(sgb.palettes, [
+            0x1234, 0x265B, 0x10B5, 0x2866,
+            0x1234, 0x11, 0x12, 0x13,
+            0x1234, 0x21, 0x22, 0x23,
+            0x1234, 0x265B, 0x10B5, 0x2866,
+        ]);
+
+        // Palette 1 on every cell colors the screen with it
+        send_packets(&mut sgb, &ppu, &[ATTR_DIV << 3 | 1, 0b010101, 0]);
+        let mut screen = ppu.get_screen().clone();
+        screen[0] = 0b10;
+        let canvas = sgb.get_canvas(&screen);
+        assert_eq!(canvas[SCREEN_Y * SGB_WIDTH + SCREEN_X], crate::ppu::color15_to_abgr(0x12));
+        assert_eq!(canvas[SCREEN_Y * SGB_WIDTH + SCREEN_X + 1], crate::ppu::color15_to_abgr(0x1234));
+        // Outside of the screen is the backdrop without a border
+        assert_eq!(canvas[0], crate::ppu::color15_to_abgr(0x1234));
+    }
+
+    #[test]
+    fn test_sgb_attributes () {
+        let ppu = PPU::new();
+        let mut sgb = Sgb::new();
+        // Inside 1, border 2, outside 3, for the box (1, 1)-(4, 3)
+        send_packets(&mut sgb, &ppu, &[ATTR_BLK << 3 | 1, 1, 0b111, 0b111001, 1, 1, 4, 3]);
+        assert_eq!(sgb.attributes[0], 3);
+        assert_eq!(sgb.attributes[ATTR_WIDTH + 1], 2);
+        assert_eq!(sgb.attributes[ATTR_WIDTH * 2 + 2], 1);
+        assert_eq!(sgb.attributes[ATTR_WIDTH * 3 + 4], 2);
+        assert_eq!(sgb.attributes[ATTR_WIDTH * 4 + 4], 3);
+
+        // Only changing the inside also changes the border
+        send_packets(&mut sgb, &ppu, &[ATTR_BLK << 3 | 1, 1, 0b001, 0b000000, 1, 1, 4, 3]);
+        assert_eq!(sgb.attributes[ATTR_WIDTH + 1], 0);
+        assert_eq!(sgb.attributes[0], 3);
+
+        // Row 5 gets palette 2
+        send_packets(&mut sgb, &ppu, &[ATTR_LIN << 3 | 1, 1, 0b11000101]);
+        assert!(sgb.attributes[ATTR_WIDTH * 5..ATTR_WIDTH * 6].iter().all(|p| *p == 2));
+
+        // Three cells across from (18, 0), wrapping onto the next row
+        send_packets(&mut sgb, &ppu, &[ATTR_CHR << 3 | 1, 18, 0, 3, 0, 0, 0b01101100]);
+        assert_eq!(sgb.attributes[18], 1);
+        assert_eq!(sgb.attributes[19], 2);
+        assert_eq!(sgb.attributes[ATTR_WIDTH], 3);
+    }
+
+    #[test]
+    fn test_sgb_multiplayer () {
+        let ppu = PPU::new();
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.read_joypad(0x3F), 0x3F);
+        send_packets(&mut sgb, &ppu, &[MLT_REQ << 3 | 1, 0b01]);
+        assert_eq!(sgb.read_joypad(0x3F), 0x3F);
+        sgb.write_joypad(0x10, &ppu);
+        sgb.write_joypad(0x30, &ppu);
+        assert_eq!(sgb.read_joypad(0x3F), 0x3E);
+        // Player 2 has nothing pressed
+        assert_eq!(sgb.read_joypad(0x10), 0x1F);
+        sgb.write_joypad(0x10, &ppu);
+        sgb.write_joypad(0x30, &ppu);
+        assert_eq!(sgb.read_joypad(0x3F), 0x3F);
+        assert_eq!(sgb.read_joypad(0x10), 0x10);
+    }
+}
@@ -0,0 +1,403 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::serial::SerialLink;
+
+// A link cable between two cli-runners on the same machine, over tcp or a unix socket.
+//
+// Both sides count cycles from when they connected, and tell each other their
+// count every SYNC_INTERVAL cycles. Whichever side gets more than MAX_LEAD cycles
+// ahead of the other waits for it to catch up, so the two never drift far apart.
+//
+// When one side finishes a transfer with its internal clock, it sends the byte along
+// with its cycle count and waits for the reply. The other side keeps running until it
+// gets to the same cycle (or answers straight away if it's already past it, which is
+// at most MAX_LEAD cycles), then replies with its SB if it was waiting on the clock,
+// or 0xFF if it wasn't.
+//
+// The other side can stop counting for a while, at a debugger break or in STOP.
+// A side waiting to catch up gives up after TIMEOUT and runs on until it hears from
+// the other side again. A transfer that gets no reply within TIMEOUT is treated as
+// the cable being pulled out.
+//
+// Every message is MESSAGE_SIZE bytes: the kind, a data byte, then the cycle as a
+// little endian u64
+const MESSAGE_SIZE: usize = 10;
+const TRANSFER: u8 = b'T';
+const REPLY: u8 = b'R';
+const SYNC: u8 = b'S';
+const SYNC_INTERVAL: u64 = 256;
+const MAX_LEAD: u64 = SYNC_INTERVAL * 2;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Message {
+    kind: u8,
+    byte: u8,
+    cycle: u64,
+}
+
+impl Message {
+    fn encode(&self) -> [u8; MESSAGE_SIZE] {
+        let mut buf = [0u8; MESSAGE_SIZE];
+        buf[0] = self.kind;
+        buf[1] = self.byte;
+        buf[2..].copy_from_slice(&self.cycle.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; MESSAGE_SIZE]) -> Self {
+        let mut cycle = [0u8; 8];
+        cycle.copy_from_slice(&buf[2..]);
+        Message {
+            kind: buf[0],
+            byte: buf[1],
+            cycle: u64::from_le_bytes(cycle),
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+    fn shutdown(&self) {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both).ok(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(Shutdown::Both).ok(),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+pub struct SocketLink {
+    stream: Stream,
+    messages: Receiver<Message>, // filled by a thread reading the socket
+    cycle: u64,
+    peer_cycle: u64, // the latest cycle the other side has told us about
+    peer_stalled: bool, // gave up waiting for the other side, until it says something
+    pending: Option<(u8, u64)>, // a transfer from the other side we haven't caught up to yet
+    connected: bool,
+    timeout: Duration,
+}
+
+// Addresses with a / in them are unix socket paths, anything else is host:port
+fn is_unix_socket(addr: &str) -> bool {
+    addr.contains('/')
+}
+
+impl SocketLink {
+    // Waits for the other side to connect
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        if is_unix_socket(addr) {
+            Self::listen_unix(addr)
+        } else {
+            let (stream, _) = TcpListener::bind(addr)?.accept()?;
+            Self::from_tcp(stream)
+        }
+    }
+
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        if is_unix_socket(addr) {
+            Self::connect_unix(addr)
+        } else {
+            Self::from_tcp(TcpStream::connect(addr)?)
+        }
+    }
+
+    fn from_tcp(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Self::new(Stream::Tcp(stream))
+    }
+
+    #[cfg(unix)]
+    fn listen_unix(path: &str) -> io::Result<Self> {
+        std::fs::remove_file(path).ok(); // left over from a previous run
+        let (stream, _) = UnixListener::bind(path)?.accept()?;
+        Self::new(Stream::Unix(stream))
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(path: &str) -> io::Result<Self> {
+        Self::new(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "unix sockets aren't supported here"))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(_path: &str) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Other, "unix sockets aren't supported here"))
+    }
+
+    fn new(stream: Stream) -> io::Result<Self> {
+        let mut reader = stream.try_clone()?;
+        let (tx, messages) = channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; MESSAGE_SIZE];
+            while reader.read_exact(&mut buf).is_ok() {
+                if tx.send(Message::decode(&buf)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            stream,
+            messages,
+            cycle: 0,
+            peer_cycle: 0,
+            peer_stalled: false,
+            pending: None,
+            connected: true,
+            timeout: TIMEOUT,
+        })
+    }
+
+    // Once the other side is gone this acts like nothing is plugged in
+    fn disconnect(&mut self) {
+        if self.connected {
+            eprintln!("Link cable disconnected");
+        }
+        self.connected = false;
+        self.pending = None;
+    }
+
+    fn recieve(&mut self, msg: &Message) {
+        self.peer_cycle = self.peer_cycle.max(msg.cycle);
+        self.peer_stalled = false;
+        if msg.kind == TRANSFER {
+            self.pending = Some((msg.byte, msg.cycle));
+        }
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) -> bool {
+        let msg = Message { kind, byte, cycle: self.cycle };
+        if !self.connected || self.stream.write_all(&msg.encode()).is_err() {
+            self.disconnect();
+        }
+        self.connected
+    }
+}
+
+// Lets the other side know we're gone, the reader thread is still holding the socket open
+impl Drop for SocketLink {
+    fn drop(&mut self) {
+        self.stream.shutdown();
+    }
+}
+
+impl SerialLink for SocketLink {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        // We're driving the clock, so we weren't waiting on theirs
+        if self.pending.take().is_some() {
+            self.send(REPLY, 0xFF);
+        }
+        if !self.send(TRANSFER, byte) {
+            return 0xFF;
+        }
+        loop {
+            match self.messages.recv_timeout(self.timeout) {
+                Ok(Message { kind: REPLY, byte, cycle }) => {
+                    self.peer_cycle = self.peer_cycle.max(cycle);
+                    return byte;
+                }
+                // They started a transfer at the same time, so neither side was waiting
+                Ok(Message { kind: TRANSFER, cycle, .. }) => {
+                    self.peer_cycle = self.peer_cycle.max(cycle);
+                    self.send(REPLY, 0xFF);
+                }
+                Ok(msg) => self.recieve(&msg),
+                Err(RecvTimeoutError::Timeout) => {
+                    eprintln!("Link cable timed out");
+                    self.disconnect();
+                    return 0xFF;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.disconnect();
+                    return 0xFF;
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        self.cycle += 1;
+        if !self.connected {
+            return None;
+        }
+        if self.cycle % SYNC_INTERVAL == 0 {
+            self.send(SYNC, 0);
+        }
+        loop {
+            match self.messages.try_recv() {
+                Ok(msg) => self.recieve(&msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect();
+                    return None;
+                }
+            }
+        }
+        // Too far ahead, wait for the other side. A transfer from them has to be
+        // answered first, as they won't go anywhere until it is
+        while self.connected && self.pending.is_none() && !self.peer_stalled
+            && self.cycle > self.peer_cycle + MAX_LEAD
+        {
+            match self.messages.recv_timeout(self.timeout) {
+                Ok(msg) => self.recieve(&msg),
+                Err(RecvTimeoutError::Timeout) => self.peer_stalled = true,
+                Err(RecvTimeoutError::Disconnected) => self.disconnect(),
+            }
+        }
+        match self.pending {
+            Some((byte, cycle)) if self.cycle >= cycle => {
+                self.pending = None;
+                self.send(REPLY, waiting.unwrap_or(0xFF));
+                waiting.map(|_| byte)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn create_test_links() -> (SocketLink, SocketLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let a = SocketLink::connect(&addr.to_string()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (a, SocketLink::from_tcp(stream).unwrap())
+    }
+
+    #[test]
+    fn test_socket_link() {
+        let (master, mut slave) = create_test_links();
+        slave.timeout = Duration::from_millis(500); // it runs ahead once the master is done
+
+        let handle = thread::spawn(move || {
+            let mut master = master;
+            for _ in 0..5000 {
+                master.poll(None);
+            }
+            let byte = master.transfer(0x11);
+            (master, byte)
+        });
+        // The slave shifts once it has caught up to the master, or straight away if
+        // it was a little ahead
+        let mut byte = None;
+        while byte.is_none() {
+            byte = slave.poll(Some(0x22));
+            assert!(slave.cycle <= 5000 + MAX_LEAD);
+        }
+        assert_eq!(byte, Some(0x11));
+        assert!(slave.cycle >= 5000);
+        let (mut master, byte) = handle.join().unwrap();
+        assert_eq!(byte, 0x22);
+
+        // Nobody waiting on the other end means nothing is shifted in
+        let (done, is_done) = channel();
+        let handle = thread::spawn(move || {
+            while is_done.try_recv().is_err() {
+                assert_eq!(slave.poll(None), None);
+            }
+            slave
+        });
+        assert_eq!(master.transfer(0x33), 0xFF);
+        done.send(()).unwrap();
+        let slave = handle.join().unwrap();
+
+        // Losing the other side is the same as having nothing plugged in
+        drop(slave);
+        assert_eq!(master.transfer(0x44), 0xFF);
+        assert_eq!(master.poll(Some(0x55)), None);
+    }
+
+    #[test]
+    fn test_socket_stall() {
+        let (mut master, mut slave) = create_test_links();
+        master.timeout = Duration::from_millis(50);
+
+        // The master can only get MAX_LEAD cycles ahead before it waits
+        for _ in 0..MAX_LEAD {
+            master.poll(None);
+        }
+        assert_eq!(master.peer_stalled, false);
+        master.poll(None);
+        // The slave never caught up, so the master gave up and carries on
+        assert_eq!(master.peer_stalled, true);
+        for _ in 0..1000 {
+            master.poll(None);
+        }
+
+        // Once the slave shows up again the master waits for it
+        for _ in 0..SYNC_INTERVAL {
+            slave.poll(None);
+        }
+        while master.peer_cycle < SYNC_INTERVAL {
+            master.poll(None);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(master.peer_cycle, SYNC_INTERVAL);
+        assert_eq!(master.peer_stalled, true); // timed out waiting again
+        assert_eq!(master.connected, true);
+
+        // A transfer nobody answers pulls the cable out
+        assert_eq!(master.transfer(0x11), 0xFF);
+        assert_eq!(master.connected, false);
+    }
+
+    #[test]
+    fn test_socket_message() {
+        let msg = Message { kind: TRANSFER, byte: 0xAB, cycle: 0x0102030405060708 };
+        let decoded = Message::decode(&msg.encode());
+        assert_eq!(decoded.kind, TRANSFER);
+        assert_eq!(decoded.byte, 0xAB);
+        assert_eq!(decoded.cycle, 0x0102030405060708);
+    }
+}
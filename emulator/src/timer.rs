@@ -1,25 +1,37 @@
 use serde::{Serialize, Deserialize};
 use crate::cpu_recievable::{Recievables, CpuRecievable::*, Interrupt};
 
+// https://gbdev.io/pandocs/#timer-obscure-behaviour
+// DIV is the top 8 bits of a 16 bit counter that goes up every clock. TIMA goes up
+// whenever the counter bit picked by TAC (anded with the enable bit) goes from 1 to 0,
+// so writing DIV or TAC can bump TIMA too.
+// When TIMA overflows it reads 0 for a cycle, then gets TMA and the interrupt fires
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum Overflow {
+    None,
+    Pending, // TIMA reads 0, writing it cancels the reload
+    Reloading, // TIMA was just loaded from TMA, writes to TIMA are ignored
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Timer {
-    div: u8, // register FF04 (increments at 16384Hz [I.E. CPU Clock / 256])
+    counter: u16, // the top 8 bits are register FF04 (increments at 16384Hz [I.E. CPU Clock / 256])
     tima: u8, // register FF05 (timer register)
     tma: u8, // register FF06 (timer modulo)
     tac: u8, // register FF07 (timer controller)
     #[serde(skip, default="crate::cpu_recievable::none_recivables")]
     recievables: Option<Recievables>,
-    ticker: usize,
+    overflow: Overflow,
 }
 
 impl Clone for Timer {
     fn clone(&self) -> Self {
         Self {
-            div: self.div,
+            counter: self.counter,
             tima: self.tima,
             tma: self.tma,
             tac: self.tac,
-            ticker: self.ticker,
+            overflow: self.overflow,
             recievables: None,
         }
     }
@@ -28,7 +40,7 @@ impl Clone for Timer {
 impl Timer {
     pub fn new() -> Timer {
         Self {
-            div: 0, tima: 0, tma: 0, tac: 0, recievables: None, ticker: 0
+            counter: 0, tima: 0, tma: 0, tac: 0, recievables: None, overflow: Overflow::None
         }
     }
 
@@ -38,61 +50,196 @@ impl Timer {
 
     pub fn read(&self, loc: u16) -> u8 {
         match loc {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0b1111_1000,
             _ => panic!("Address should not have been routed to timer"),
         }
 
     }
 
     pub fn write(&mut self, loc: u16, val: u8) {
+        let signal = self.signal();
         match loc {
-            0xFF04 => self.div = 0,
-            0xFF05 => self.tima = val,
-            0xFF06 => self.tma = val,
-            0xFF07 => self.tac = val,
+            0xFF04 => self.counter = 0,
+            0xFF05 => match self.overflow {
+                Overflow::Pending => {
+                    self.overflow = Overflow::None;
+                    self.tima = val;
+                }
+                Overflow::Reloading => (),
+                Overflow::None => self.tima = val,
+            },
+            0xFF06 => {
+                self.tma = val;
+                if self.overflow == Overflow::Reloading {
+                    self.tima = val;
+                }
+            }
+            0xFF07 => self.tac = val & 0b111,
             _ => panic!("Address should not have been routed to timer"),
         }
+        if signal && !self.signal() {
+            self.increment_tima();
+        }
     }
 
+    // Each tick is one cpu cycle, 4 clocks
     pub fn tick(&mut self) {
-        self.ticker = (self.ticker + 1) & 0xFFFF;
-        let divider_clock = 8; // 2^8 = 256
-        if self.ticker & ((1 << divider_clock) - 1) == 0 {
-            self.div = self.div.wrapping_add(1);
-        }
-
-        if self.is_timer_enabled() {
-            let timer_clock = self.timer_clock();
-            if self.ticker & ((1 << timer_clock) - 1) == 0 {
-                if self.tima == 0xFF {
-                    self.tima = self.tma;
-                    match &self.recievables {
-                        Some(r) => r.send(SendInterrupt(Interrupt::Timer)),
-                        None => ()
-                    }
-                } else {
-                    self.tima += 1
+        match self.overflow {
+            Overflow::Pending => {
+                self.tima = self.tma;
+                self.overflow = Overflow::Reloading;
+                match &self.recievables {
+                    Some(r) => r.send(SendInterrupt(Interrupt::Timer)),
+                    None => ()
                 }
             }
+            Overflow::Reloading => self.overflow = Overflow::None,
+            Overflow::None => (),
         }
 
+        let signal = self.signal();
+        self.counter = self.counter.wrapping_add(4);
+        if signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
 
+    fn increment_tima(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.overflow = Overflow::Pending;
+        } else {
+            self.tima += 1
+        }
+    }
+
+    // The counter bit TIMA watches for a falling edge
+    fn signal(&self) -> bool {
+        self.is_timer_enabled() && self.counter & (1 << self.timer_bit()) != 0
     }
 
     fn is_timer_enabled(&self) -> bool {
         self.tac & 0b100 != 0
     }
 
-    fn timer_clock(&self) -> usize {
+    fn timer_bit(&self) -> usize {
         match self.tac & 0b11 {
-            0b00 => 10, // 2^10 = 1024
-            0b01 => 4, // 2^4 = 16
-            0b10 => 6, // 2^6 = 64
-            0b11 => 8,// 2^8 = 256
+            0b00 => 9, // 2^10 = 1024
+            0b01 => 3, // 2^4 = 16
+            0b10 => 5, // 2^6 = 64
+            0b11 => 7, // 2^8 = 256
             _ => panic!("This should be impossible")
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count_timer_interrupts(r: &Recievables) -> usize {
+        let mut count = 0;
+        while let Some(x) = r.recieve() {
+            if let SendInterrupt(Interrupt::Timer) = x {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    fn create_test_timer() -> (Timer, Recievables) {
+        let mut timer = Timer::new();
+        let r = Recievables::new();
+        timer.set_recievables(r.clone());
+        (timer, r)
+    }
+
+    #[test]
+    fn test_timer_rates() {
+        let (mut timer, _r) = create_test_timer();
+        // DIV goes up every 64 cycles
+        for _ in 0..64 {
+            timer.tick();
+        }
+        assert_eq!(timer.read(0xFF04), 1);
+        timer.write(0xFF04, 0x55);
+        assert_eq!(timer.read(0xFF04), 0);
+
+        // TAC 0b01 is every 4 cycles, 0b10 every 16, 0b11 every 64 and 0b00 every 256
+        for (tac, cycles) in [(0b101, 4), (0b110, 16), (0b111, 64), (0b100, 256)].iter() {
+            timer.write(0xFF04, 0);
+            timer.write(0xFF05, 0);
+            timer.write(0xFF07, *tac);
+            for _ in 0..(cycles * 3) {
+                timer.tick();
+            }
+            assert_eq!(timer.read(0xFF05), 3);
+        }
+        assert_eq!(timer.read(0xFF07), 0xFC);
+    }
+
+    #[test]
+    fn test_timer_glitches() {
+        let (mut timer, _r) = create_test_timer();
+        timer.write(0xFF07, 0b101);
+        // Bit 3 of the counter is set after 2 cycles, resetting DIV makes it fall
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.read(0xFF05), 0);
+        timer.write(0xFF04, 0);
+        assert_eq!(timer.read(0xFF05), 1);
+
+        // So does turning the timer off, or picking a bit that's clear
+        timer.tick();
+        timer.tick();
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.read(0xFF05), 2);
+        timer.write(0xFF07, 0b101);
+        timer.write(0xFF07, 0b110);
+        assert_eq!(timer.read(0xFF05), 3);
+
+        // Nothing happens if the bit was already clear
+        timer.write(0xFF04, 0);
+        timer.write(0xFF07, 0b000);
+        assert_eq!(timer.read(0xFF05), 3);
+    }
+
+    #[test]
+    fn test_timer_overflow() {
+        let (mut timer, r) = create_test_timer();
+        timer.write(0xFF06, 0x42);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0b101);
+        for _ in 0..4 {
+            timer.tick();
+        }
+        // TIMA is 0 for a cycle before TMA is loaded and the interrupt fires
+        assert_eq!(timer.read(0xFF05), 0);
+        assert_eq!(count_timer_interrupts(&r), 0);
+        timer.tick();
+        assert_eq!(timer.read(0xFF05), 0x42);
+        assert_eq!(count_timer_interrupts(&r), 1);
+
+        // Writing TIMA during the reload cycle is ignored, but TMA goes straight through
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x42);
+        timer.write(0xFF06, 0x50);
+        assert_eq!(timer.read(0xFF05), 0x50);
+        timer.tick();
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.read(0xFF05), 0x10);
+
+        // Writing TIMA while it reads 0 cancels the reload and the interrupt
+        timer.write(0xFF05, 0xFF);
+        while timer.read(0xFF05) != 0 {
+            timer.tick();
+        }
+        timer.write(0xFF05, 0x20);
+        timer.tick();
+        assert_eq!(timer.read(0xFF05), 0x20);
+        assert_eq!(count_timer_interrupts(&r), 0);
+    }
+}
@@ -1,11 +1,16 @@
 import localForage from 'localforage';
-interface Wasm {
+interface WasmEmulator {
     button_down: Function,
     button_up: Function,
     update: Function,
     init: Function,
     save_state: Function,
     load_state: Function,
+    is_rumbling: Function,
+    is_double_speed: Function,
+    export_save_ram: Function,
+    import_save_ram: Function,
+    rom_info: Function,
 }
 
 export enum Button {
@@ -24,22 +29,31 @@ interface SaveState {
 }
 
 export default class Emulator {
-    wasm: Wasm | null = null
+    wasm: WasmEmulator | null = null
+    loaded: boolean = false
+    info: any = undefined
 
     constructor() {
         (async () => {
           await import("rust/gameboy_emulator_bg.wasm");
           const wasm = await import("rust/gameboy_emulator");
-          this.wasm = wasm;
+          this.wasm = new wasm.Emulator();
         })()
     }
 
     load_rom(data: Uint8Array) {
         let w: any = window;
         w.lf = localForage
-        if (!w.has_loaded) {
-            this.wasm?.init(data);
-            w.has_loaded = true;
+        if (!this.loaded) {
+            try {
+                this.wasm?.init(data);
+            } catch (e) {
+                console.error("Failed to load rom: ", e);
+                alert(`Failed to load rom: ${e}`);
+                return;
+            }
+            this.loaded = this.wasm !== null;
+            this.info = this.rom_info();
         }
         w.button_down = (e: Button) => this.button_down(e)
         w.button_up = (e: Button) => this.button_up(e)
@@ -47,13 +61,22 @@ export default class Emulator {
 
     button_down(b: Button): number {
         console.log("Sending Button press: ", b);
+        if (!this.loaded) {
+            return 0;
+        }
         return this.wasm?.button_down(b);
     }
     button_up(b: Button): number {
+        if (!this.loaded) {
+            return 0;
+        }
         return this.wasm?.button_up(b);
     }
 
     make_save_state() {
+        if (!this.loaded) {
+            return;
+        }
         let data: Uint8Array = this.wasm?.save_state();
         let save: SaveState = {
             date: Date.now(),
@@ -70,6 +93,9 @@ export default class Emulator {
     }
 
     load_save_state() {
+        if (!this.loaded) {
+            return;
+        }
         localForage.getItem("saves").then((saves: any) => {
             // saves: Array<SaveState> | null
             if (saves === null) {
@@ -78,16 +104,53 @@ export default class Emulator {
             }
             let save = saves[saves.length - 1];
             console.log("Loading save ", save);
-            this.wasm?.load_state(save.data);
+            try {
+                this.wasm?.load_state(save.data);
+            } catch (e) {
+                console.error("Failed to load save state: ", e);
+            }
         })
 
     }
 
+    rom_info(): any {
+        if (!this.loaded) {
+            return undefined;
+        }
+        return this.wasm?.rom_info();
+    }
+
+    export_save_ram(): Uint8Array | undefined {
+        if (!this.loaded) {
+            return undefined;
+        }
+        return this.wasm?.export_save_ram();
+    }
+
+    import_save_ram(data: Uint8Array) {
+        if (!this.loaded) {
+            return;
+        }
+        this.wasm?.import_save_ram(data);
+    }
+
+    is_rumbling(): boolean {
+        if (!this.loaded) {
+            return false;
+        }
+        return this.wasm?.is_rumbling() ?? false;
+    }
+
     update() {
         // TODO: something with CPU timing
         let cycles_per_frame = 70256;
         let cycles_per_second = 4194304;
-        return this.wasm?.update(70256 / 2);
+        if (!this.loaded) {
+            return undefined;
+        }
+        // CGB double speed needs twice the ticks for the same amount of frame time
+        let speed = this.wasm?.is_double_speed() ? 2 : 1;
+        return this.wasm?.update(speed * 70256 / 2);
     }
 
 }
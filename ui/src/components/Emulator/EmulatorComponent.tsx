@@ -34,6 +34,9 @@ export const EmulatorScreen = (props: EmulatorProps) => {
             let data = emulator.update();
             imd.data.set(new Uint8ClampedArray(data.buffer));
             ctx.putImageData(imd, 0, 0);
+            if (emulator.is_rumbling() && navigator.vibrate) {
+                navigator.vibrate(diff);
+            }
             ani = requestAnimationFrame(checker);
         };
         if (romdata) {
@@ -56,8 +59,15 @@ export const EmulatorScreen = (props: EmulatorProps) => {
         })
     }
 
+    let info = emulator.info;
     return (
-        <canvas id={id} width={160} height={144}></canvas>
+        <>
+            <canvas id={id} width={160} height={144}></canvas>
+            {info && <p className="rom-info">{info.title} ({info.cartridge_type_name})</p>}
+            {/* Real carts never fail this one, the boot rom would refuse to start them */}
+            {info && !info.header_checksum_valid &&
+                <p className="rom-warning">Header checksum mismatch, this is probably a corrupt dump</p>}
+        </>
     )
 }
 